@@ -5,8 +5,13 @@ use {
     rand::{RngCore, SeedableRng},
     rand_pcg::Pcg64,
     rayon::iter::{IntoParallelRefIterator, ParallelIterator},
-    save::git2::*,
-    std::{path::PathBuf, time::Duration},
+    save::{
+        git2::*,
+        guardrails::Guardrails,
+        hash::{digest_object, sha1_object, Sha1Backend},
+        object::RawCommit,
+    },
+    std::{fs, path::PathBuf, time::Duration},
 };
 
 criterion_main!(benches);
@@ -14,7 +19,10 @@ criterion_group! {
     benches,
     bench_oid_from_bytes,
     bench_hash_git_object,
+    bench_sha1_backends,
+    bench_brute_force_candidates,
     bench_generation_number,
+    bench_working_tree,
 }
 
 fn bench_oid_from_bytes(c: &mut Criterion) {
@@ -122,6 +130,94 @@ fn bench_hash_git_object(c: &mut Criterion) {
     }
 }
 
+fn bench_sha1_backends(c: &mut Criterion) {
+    let mut c = c.benchmark_group("comparing sha-1 backends");
+
+    let mut rng = Pcg64::seed_from_u64(0);
+    let bodies = (0..4_096)
+        .map(|_| {
+            let mut body = vec![0u8; 512];
+            rng.fill_bytes(&mut body);
+            body
+        })
+        .collect::<Vec<_>>();
+
+    c.throughput(Throughput::Bytes((bodies.len() * 512).try_into().unwrap()));
+
+    c.bench_with_input("4K 512B objects sha1 (RustCrypto)", &bodies, |b, bodies| {
+        b.iter(|| {
+            for body in bodies.iter() {
+                black_box(digest_object::<sha1::Sha1>("commit", body));
+            }
+        })
+    });
+
+    let label = if cfg!(feature = "sha1dc") {
+        "4K 512B objects sha1_object (save, sha1dc)"
+    } else {
+        "4K 512B objects sha1_object (save, sha1)"
+    };
+    c.bench_with_input(label, &bodies, |b, bodies| {
+        b.iter(|| {
+            for body in bodies.iter() {
+                black_box(sha1_object("commit", body));
+            }
+        })
+    });
+}
+
+fn bench_brute_force_candidates(c: &mut Criterion) {
+    let mut c = c.benchmark_group("hashing brute force candidates");
+
+    let signature =
+        git2::Signature::new("Jeremy", "jeremy@example.com", &git2::Time::new(0, 0)).unwrap();
+    let base = RawCommit::new(
+        Oid::for_object("tree", &[]),
+        &[Oid::for_object("commit", b"parent")],
+        &signature,
+        &signature,
+        "r1/1f2e/3d4c\n",
+    );
+
+    let candidates = (1_643_000_000..1_643_000_000 + 4_096)
+        .map(|timestamp| {
+            let mut candidate = base.clone();
+            candidate.committer.seconds = timestamp;
+            candidate.to_bytes()
+        })
+        .collect::<Vec<_>>();
+
+    c.throughput(Throughput::Elements(candidates.len().try_into().unwrap()));
+
+    c.bench_with_input(
+        "4K candidates for_object (save)",
+        &candidates,
+        |b, candidates| {
+            b.iter(|| {
+                for candidate in candidates.iter() {
+                    black_box(Oid::for_object("commit", candidate));
+                }
+            })
+        },
+    );
+
+    for backend in Sha1Backend::ALL {
+        if backend.is_available() {
+            c.bench_with_input(
+                format!("4K candidates hash_objects ({backend:?})"),
+                &candidates,
+                |b, candidates| b.iter(|| black_box(backend.hash_objects("commit", candidates))),
+            );
+        }
+    }
+
+    c.bench_with_input(
+        "4K candidates for_objects (save)",
+        &candidates,
+        |b, candidates| b.iter(|| black_box(Oid::for_objects("commit", candidates))),
+    );
+}
+
 fn bench_generation_number(c: &mut Criterion) {
     let mut c = c.benchmark_group("measuring generation numbers");
 
@@ -149,3 +245,45 @@ fn bench_generation_number(c: &mut Criterion) {
         });
     }
 }
+
+fn bench_working_tree(c: &mut Criterion) {
+    let mut c = c.benchmark_group("snapshotting working trees");
+    c.measurement_time(12 * Duration::from_secs(1));
+
+    let repo = Repository::temporary().unwrap();
+    let workdir = repo.workdir().unwrap().to_path_buf();
+
+    let mut rng = Pcg64::seed_from_u64(0);
+    let mut total_len = 0;
+    for i in 0..4_096 {
+        let dir = workdir.join(format!("{:02x}", i % 64));
+        fs::create_dir_all(&dir).unwrap();
+        let len = (rng.next_u64() % 32_768).try_into().unwrap();
+        total_len += len;
+        let mut body = vec![0u8; len];
+        rng.fill_bytes(&mut body);
+        fs::write(dir.join(format!("{i}.bin")), body).unwrap();
+    }
+
+    let expected = repo
+        .working_index_snapshot(&[], &Guardrails::disabled())
+        .unwrap()
+        .write_tree_to(&repo)
+        .unwrap();
+    assert_eq!(expected, repo.working_tree_id().unwrap());
+
+    c.throughput(Throughput::Bytes(total_len.try_into().unwrap()));
+
+    c.bench_function("4K 16KB files add_all + write_tree (git2)", |b| {
+        b.iter(|| {
+            repo.working_index_snapshot(&[], &Guardrails::disabled())
+                .unwrap()
+                .write_tree_to(&repo)
+                .unwrap()
+        })
+    });
+
+    c.bench_function("4K 16KB files rayon-parallel working_tree_id (save)", |b| {
+        b.iter(|| repo.working_tree_id().unwrap())
+    });
+}
@@ -55,16 +55,42 @@ fn bench_hash_object_save_large() {
     black_box(Oid::for_object("commit", black_box(LARGE_BODY.as_ref())));
 }
 
+fn bench_hash_candidates_save() {
+    for candidate in CANDIDATES.iter() {
+        black_box(Oid::for_object("commit", black_box(candidate.as_ref())));
+    }
+}
+
+fn bench_hash_candidates_save_batched() {
+    black_box(Oid::for_objects("commit", black_box(CANDIDATES.as_ref())));
+}
+
 main!(
     bench_hash_object_git2,
     bench_hash_object_save,
     bench_hash_object_git2_large,
-    bench_hash_object_save_large
+    bench_hash_object_save_large,
+    bench_hash_candidates_save,
+    bench_hash_candidates_save_batched
 );
 
 static SMALL_BODY: [u8; 512] = assorted_bytes();
 static LARGE_BODY: [u8; 1_048_576] = assorted_bytes();
 
+/// 64 brute force candidates, each a slightly different 512-byte body.
+static CANDIDATES: [[u8; 512]; 64] = candidates();
+
+const fn candidates<const COUNT: usize>() -> [[u8; 512]; COUNT] {
+    let mut candidates = [assorted_bytes(); COUNT];
+    let mut i = 0;
+    while i < COUNT {
+        candidates[i][256] = i as u8;
+        i += 1;
+    }
+
+    candidates
+}
+
 const fn assorted_bytes<const LENGTH: usize>() -> [u8; LENGTH] {
     let mut bytes = [0u8; LENGTH];
     let mut i = 0;
@@ -2,34 +2,42 @@
 
 #[allow(unused)]
 pub(self) use git2::{
-    Blob, Branch, Commit, Config, Index, Object, ObjectType, Oid, Reference, Remote, Repository,
-    Signature, Tag, Time, Tree,
+    Blob, Branch, Commit, Config, ErrorCode, Index, Object, ObjectType, Oid, Reference, Remote,
+    Repository, Signature, Tag, Time, Tree,
 };
 use {
-    digest::{generic_array::GenericArray, Digest},
-    eyre::{Context, Result},
+    crate::{
+        guardrails::{GuardAction, Guardrails},
+        hash::{sha1_object, Sha1Backend},
+        lfs,
+        object::{RawCommit, RawTag},
+        search::{self, Checkpoint, Shard, Widening},
+        signing::CommitSigner,
+        tree,
+    },
+    eyre::{bail, Context, Result},
     itertools::Itertools,
     petgraph::{
         graphmap::DiGraphMap,
         visit::Topo,
         EdgeDirection::{Incoming, Outgoing},
     },
-    rayon::iter::{IntoParallelIterator, ParallelIterator},
     std::{
         borrow::Borrow,
         cell::RefCell,
         cmp::max,
         collections::{HashMap, HashSet},
-        fmt::Debug,
+        fmt::{self, Debug},
+        fs,
         intrinsics::transmute,
         ops::{Deref, DerefMut},
         path::PathBuf,
         rc::Rc,
+        time::{Duration, Instant},
     },
     tempfile::TempDir,
     thousands::Separable,
     tracing::{debug, debug_span, info, instrument, trace, warn},
-    typenum::U20,
 };
 
 /// Extension methods for [`Repository`].
@@ -38,6 +46,17 @@ pub trait RepositoryExt: Borrow<Repository> {
     /// tree, as though everything inside of it had been committed on top of
     /// the current head. Submodules are skipped with a warning logged.
     ///
+    /// If any `pathspecs` are specified, only matching paths are updated from
+    /// the working tree, and all other paths are reset to their contents in
+    /// the current head, discarding anything else that was staged for them.
+    /// Exclusions may be written as `:!path`, `:^path`, or `:(exclude)path`.
+    ///
+    /// Files tracked by Git LFS are replaced by LFS pointers; their content
+    /// must be stored afterwards with [`lfs::store_objects`]. Other new and
+    /// modified files are checked against `guardrails`; depending on
+    /// its [`GuardAction`], any that are rejected either cause an error
+    /// listing them all, or are left out with a warning logged.
+    ///
     /// These changes can be written back to the repository index on disk with
     /// [`Index::write`], or converted into a [`Tree`] with
     /// [`Index::write_tree`].
@@ -47,7 +66,7 @@ pub trait RepositoryExt: Borrow<Repository> {
     /// If the repository is bare (per [`Repository::is_bare`]).
     #[instrument(level = "debug", skip_all)]
     #[must_use]
-    fn working_index(&self) -> Result<Index> {
+    fn working_index(&self, pathspecs: &[&str], guardrails: &Guardrails) -> Result<Index> {
         let repo: &Repository = self.borrow();
 
         if repo.is_bare() {
@@ -55,30 +74,274 @@ pub trait RepositoryExt: Borrow<Repository> {
         }
 
         let mut index = repo.index()?;
-        index
-            .add_all(
-                ["*"],
-                Default::default(),
-                Some(&mut |path, _| {
-                    if path.to_string_lossy().ends_with('/') {
-                        let mut git_path = PathBuf::from(path);
-                        git_path.push(".git");
-                        if git_path.is_dir() {
-                            warn!(
-                                "Encountered a Git submodule; skipping it: {}",
-                                git_path.to_string_lossy()
-                            );
-                            return 1;
-                        }
-                    }
-                    trace!("Adding: {}", path.to_string_lossy());
-                    0
-                }),
-            )
-            .wrap_err("Failed to add something to the Git index.")?;
+        add_working_tree_to_index(repo, &mut index, pathspecs, guardrails)?;
         Ok(index)
     }
 
+    /// Like [`RepositoryExt::working_index`], but the returned Index is a
+    /// detached in-memory snapshot. The repository's own index is left
+    /// untouched, both on disk and in this [`Repository`] handle.
+    ///
+    /// Because the snapshot doesn't belong to the repository, it must be
+    /// converted into a [`Tree`] with [`Index::write_tree_to`].
+    ///
+    /// # Panics
+    ///
+    /// If the repository is bare (per [`Repository::is_bare`]).
+    #[instrument(level = "debug", skip_all)]
+    #[must_use]
+    fn working_index_snapshot(&self, pathspecs: &[&str], guardrails: &Guardrails) -> Result<Index> {
+        let repo: &Repository = self.borrow();
+
+        if repo.is_bare() {
+            panic!("Repository is bare!");
+        }
+
+        // libgit2 can only add files from the working tree to an index that
+        // belongs to a repository, so we give a copy of the index file to a
+        // second handle for the same repository, sharing its object database.
+        let dir = TempDir::new()?;
+        let path = dir.path().join("index");
+        if let Some(existing) = repo.index()?.path() {
+            if existing.exists() {
+                fs::copy(existing, &path)?;
+            }
+        }
+        let mut index = Index::open(&path)?;
+
+        let snapshot = Repository::open(repo.path())?;
+        snapshot.set_odb(&repo.odb()?)?;
+        if let Some(workdir) = repo.workdir() {
+            snapshot.set_workdir(workdir, false)?;
+        }
+        snapshot.set_index(&mut index)?;
+
+        add_working_tree_to_index(&snapshot, &mut index, pathspecs, guardrails)?;
+        Ok(index)
+    }
+
+    /// Returns the ID of a tree with the current contents of the repository's
+    /// working tree, like [`RepositoryExt::working_index`] with no pathspecs
+    /// or guardrails, but hashed natively in parallel without an index. See
+    /// [`tree::working_tree_id`] for what is and isn't supported. `save`
+    /// doesn't use this.
+    ///
+    /// Nothing is written to the object database, so the tree may not exist.
+    ///
+    /// # Errors
+    ///
+    /// If the repository is bare, if the working tree can't be read, or if
+    /// Git's line ending or `ident` filters would apply to any file.
+    #[instrument(level = "debug", skip_all)]
+    fn working_tree_id(&self) -> Result<Oid> {
+        tree::working_tree_id(self.borrow(), false)
+    }
+
+    /// Like [`RepositoryExt::working_tree_id`], but also writes any missing
+    /// blobs and trees to the object database.
+    ///
+    /// # Errors
+    ///
+    /// If the repository is bare, if the working tree can't be read or
+    /// written, or if Git's line ending or `ident` filters would apply to any
+    /// file.
+    #[instrument(level = "debug", skip_all)]
+    fn write_working_tree(&self) -> Result<Oid> {
+        tree::working_tree_id(self.borrow(), true)
+    }
+
+    /// Like [`CommitExt::brute_force_timestamps`], but starting from a commit
+    /// that only exists in memory, such as one built with [`RawCommit::new`],
+    /// so that the brute-forced commit is the only object written.
+    ///
+    /// If `signer` is specified, every candidate is signed, and only a few
+    /// timestamps are tried, as in
+    /// [`CommitExt::brute_force_timestamps_signed`]. Otherwise, progress is
+    /// saved under `.git/save/` as the search goes, so that if it's
+    /// interrupted, the next search for the same commit and target resumes it.
+    ///
+    /// # Errors
+    ///
+    /// If signing any candidate fails, or if the commit can't be written.
+    ///
+    /// # Panics
+    ///
+    /// If `min_timestamp` > `max_timestamp`.
+    #[instrument(level = "debug", skip_all)]
+    fn brute_force_commit(
+        &self,
+        base_commit: &RawCommit,
+        target_prefix: &[u8],
+        min_timestamp: impl Into<Option<i64>>,
+        max_timestamp: impl Into<Option<i64>>,
+        signer: Option<&CommitSigner>,
+    ) -> Result<BruteForcedCommit<'_>> {
+        brute_force_timestamps(
+            base_commit,
+            self.borrow(),
+            target_prefix,
+            min_timestamp.into(),
+            max_timestamp.into(),
+            signer,
+            None,
+            true,
+        )
+    }
+
+    /// Like [`RepositoryExt::brute_force_commit`], but only requiring the
+    /// first `widening.required_nibbles` hex digits of `target_prefix` to
+    /// match, and searching wider timestamp windows starting at
+    /// `min_timestamp` until they do, or `widening.budget` runs out. The
+    /// first window is `window` seconds wide. Searches for signed commits
+    /// aren't widened.
+    ///
+    /// If `checkpoints` is false, no [`Checkpoint`] is loaded from, saved in,
+    /// or removed from `.git/save/`, such as for a dry run.
+    ///
+    /// # Errors
+    ///
+    /// If signing any candidate fails, or if the commit can't be written.
+    ///
+    /// # Panics
+    ///
+    /// If `window` isn't positive.
+    #[instrument(level = "debug", skip_all)]
+    #[allow(clippy::too_many_arguments)]
+    fn brute_force_commit_widening(
+        &self,
+        base_commit: &RawCommit,
+        target_prefix: &[u8],
+        min_timestamp: i64,
+        window: i64,
+        widening: Widening,
+        signer: Option<&CommitSigner>,
+        checkpoints: bool,
+    ) -> Result<BruteForcedCommit<'_>> {
+        assert!(window > 0, "window must be positive");
+        brute_force_timestamps(
+            base_commit,
+            self.borrow(),
+            target_prefix,
+            Some(min_timestamp),
+            Some(min_timestamp.saturating_add(window - 1)),
+            signer,
+            Some(widening),
+            checkpoints,
+        )
+    }
+
+    /// Like [`RepositoryExt::brute_force_commit_widening`], but keeping the
+    /// author timestamp of `base_commit` and only varying its committer
+    /// timestamp, from `min_timestamp`. There's only one timestamp to vary, so
+    /// each wider step only searches the timestamps that weren't in the last.
+    /// Nothing is signed, and no [`Checkpoint`] is saved.
+    ///
+    /// # Errors
+    ///
+    /// If the commit can't be written.
+    ///
+    /// # Panics
+    ///
+    /// If `window` isn't positive.
+    #[instrument(level = "debug", skip_all)]
+    fn brute_force_committer_timestamp(
+        &self,
+        base_commit: &RawCommit,
+        target_prefix: &[u8],
+        min_timestamp: i64,
+        window: i64,
+        widening: Widening,
+    ) -> Result<BruteForcedCommit<'_>> {
+        assert!(window > 0, "window must be positive");
+        let repo: &Repository = self.borrow();
+
+        let mut timestamps = min_timestamp..=min_timestamp.saturating_add(window - 1);
+        let start = Instant::now();
+        let mut best = None;
+        let mut candidates = 0_u64;
+        loop {
+            let steps = u64::try_from(*timestamps.end() - *timestamps.start() + 1)?;
+            let step_best =
+                search::best_committer_candidate(base_commit, target_prefix, timestamps.clone());
+            best = best.into_iter().chain(step_best).min();
+            candidates = candidates.saturating_add(steps);
+
+            let candidate = best.take().expect("there's always a candidate");
+            let quality = candidate.quality(target_prefix, candidates, min_timestamp);
+            // the next step is as large as everything so far
+            if quality.matching_nibbles >= widening.required_nibbles
+                || start.elapsed() * 2 >= widening.budget
+            {
+                return candidate.write(repo, quality, widening.required_nibbles);
+            }
+            best = Some(candidate);
+
+            let searched = *timestamps.end() - min_timestamp + 1;
+            timestamps = *timestamps.end() + 1..=min_timestamp.saturating_add(searched * 2) - 1;
+            info!(
+                "Best candidate only matched {} of {} hex digits; searching {} more committer \
+                 timestamps.",
+                quality.matching_nibbles,
+                widening.required_nibbles,
+                searched.separate_with_underscores()
+            );
+        }
+    }
+
+    /// Like [`RepositoryExt::brute_force_commit_widening`], but for an
+    /// annotated tag that only exists in memory, such as one built with
+    /// [`RawTag::new`], varying its tagger timestamp from `min_timestamp` to
+    /// `max_timestamp`.
+    ///
+    /// If that doesn't find a match, and there's time left in the
+    /// `widening` budget, the search continues with twice as many
+    /// candidates each time: either adding a `nonce` header to the tag (see
+    /// [`RawTag::set_nonce`]) if `nonce` is true, or else with later
+    /// timestamps.
+    ///
+    /// # Errors
+    ///
+    /// If the tag has no tagger, if it's signed (which would invalidate the
+    /// signature), or if it can't be written.
+    ///
+    /// # Panics
+    ///
+    /// If `min_timestamp` > `max_timestamp`.
+    #[instrument(level = "debug", skip_all)]
+    fn brute_force_tag(
+        &self,
+        base_tag: &RawTag,
+        target_prefix: &[u8],
+        min_timestamp: i64,
+        max_timestamp: i64,
+        nonce: bool,
+        widening: Widening,
+    ) -> Result<BruteForcedTag<'_>> {
+        brute_force_tag(
+            base_tag,
+            self.borrow(),
+            target_prefix,
+            min_timestamp,
+            max_timestamp,
+            nonce,
+            widening,
+        )
+    }
+
+    /// Redirects all further object writes through this [`Repository`] handle
+    /// into memory, so that nothing new is written to the object database on
+    /// disk. Objects written this way can still be read through this handle.
+    ///
+    /// # Errors
+    ///
+    /// If the object database can't be opened.
+    #[instrument(level = "debug", skip_all)]
+    fn write_objects_in_memory(&self) -> Result<()> {
+        let repo: &Repository = self.borrow();
+        repo.odb()?.add_new_mempack_backend(i32::MAX)?;
+        Ok(())
+    }
+
     /// Creates a [`Repository`] backed by a new temporary directory.
     #[instrument(level = "debug", skip_all)]
     #[must_use]
@@ -114,10 +377,12 @@ pub trait RepositoryExt: Borrow<Repository> {
     fn save(&self) -> Result<Commit> {
         let repo: &Repository = self.borrow();
 
-        let mut index = self.working_index()?;
+        let guardrails = Guardrails::from_config(&repo.config()?)?;
+        let mut index = self.working_index(&[], &guardrails)?;
         let tree = index.write_tree()?;
         let tree = repo.find_tree(tree)?;
         let head = repo.head()?.peel_to_commit()?;
+        lfs::store_objects(repo, Some(&head.tree()?), &tree)?;
         let signature = repo.signature_or_fallback();
         let message = "hmm";
         let commit = repo.commit(None, &signature, &signature, message, &tree, &[&head])?;
@@ -128,6 +393,109 @@ pub trait RepositoryExt: Borrow<Repository> {
 
 impl<T> RepositoryExt for T where T: Borrow<Repository> {}
 
+/// Updates `index` with the contents of the working tree, for
+/// [`RepositoryExt::working_index`] and
+/// [`RepositoryExt::working_index_snapshot`].
+fn add_working_tree_to_index(
+    repo: &Repository,
+    index: &mut Index,
+    pathspecs: &[&str],
+    guardrails: &Guardrails,
+) -> Result<()> {
+    let pathspecs = if pathspecs.is_empty() {
+        vec!["*".to_string()]
+    } else {
+        match repo.head() {
+            Ok(head) => index.read_tree(&head.peel_to_tree()?)?,
+            Err(err) if err.code() == ErrorCode::UnbornBranch => index.clear()?,
+            Err(err) => return Err(err.into()),
+        }
+        libgit2_pathspecs(pathspecs)
+    };
+    debug!("Adding paths matching: {:?}", pathspecs);
+
+    let workdir = repo.workdir().map(PathBuf::from).unwrap_or_default();
+    let mut rejected = vec![];
+    let mut lfs_paths = vec![];
+
+    index
+        .add_all(
+            &pathspecs,
+            Default::default(),
+            Some(&mut |path, _| {
+                if path.to_string_lossy().ends_with('/') {
+                    let mut git_path = PathBuf::from(path);
+                    git_path.push(".git");
+                    if git_path.is_dir() {
+                        warn!(
+                            "Encountered a Git submodule; skipping it: {}",
+                            git_path.to_string_lossy()
+                        );
+                        return 1;
+                    }
+                }
+                if lfs::is_tracked(repo, path) && workdir.join(path).is_file() {
+                    lfs_paths.push(path.to_path_buf());
+                    return 1;
+                }
+                if let Some(violation) = guardrails.check(&workdir, path) {
+                    if guardrails.action == GuardAction::Skip {
+                        warn!("Skipping {}: {}", path.to_string_lossy(), violation);
+                    }
+                    rejected.push(format!("  {}: {}", path.to_string_lossy(), violation));
+                    return 1;
+                }
+                trace!("Adding: {}", path.to_string_lossy());
+                0
+            }),
+        )
+        .wrap_err("Failed to add something to the Git index.")?;
+
+    if !rejected.is_empty() && guardrails.action == GuardAction::Abort {
+        bail!(
+            "Refusing to save {} file(s) that look like mistakes (use --force to save them \
+             anyway):\n{}",
+            rejected.len(),
+            rejected.join("\n")
+        );
+    }
+
+    for path in lfs_paths {
+        lfs::stage(index, &workdir, &path)?;
+    }
+
+    Ok(())
+}
+
+/// Converts Git pathspecs into the subset of that syntax supported by
+/// `libgit2`, where the first matching pattern wins and exclusions are
+/// written as `!pattern`.
+///
+/// Exclusions are moved to the front so they take precedence, and if there
+/// are only exclusions we add a `*` to include everything else, as Git does.
+fn libgit2_pathspecs(pathspecs: &[&str]) -> Vec<String> {
+    let mut exclusions = vec![];
+    let mut inclusions = vec![];
+
+    for pathspec in pathspecs {
+        if let Some(excluded) = [":(exclude)", ":!", ":^"]
+            .iter()
+            .find_map(|magic| pathspec.strip_prefix(magic))
+        {
+            exclusions.push(format!("!{}", excluded));
+        } else {
+            inclusions.push(pathspec.to_string());
+        }
+    }
+
+    if inclusions.is_empty() {
+        inclusions.push("*".to_string());
+    }
+
+    exclusions.extend(inclusions);
+    exclusions
+}
+
 /// A [`Repository`] in a temporary directory.
 ///
 /// Because the backing directory for the repository will be deleted when this
@@ -416,8 +784,9 @@ pub trait CommitExt<'repo>: Borrow<Commit<'repo>> + Debug {
     /// the "author" timestamp, so this searches half the square of the number
     /// of possible timestamps in the range. If multiple complete matches for
     /// the prefix exist within the time span, this function will return the
-    /// one with the lowest committer timestamp, and if that's a tie it will
-    /// use the one with the lowest author timestamp.
+    /// first in the [search order](crate::search#order): the one with the
+    /// smallest delta between its timestamps, and if that's a tie, the one
+    /// with the lowest author timestamp.
     ///
     /// If `min_timestamp` is not specified, it will default to the current
     /// committer timestamp in the commit.
@@ -427,7 +796,8 @@ pub trait CommitExt<'repo>: Borrow<Commit<'repo>> + Debug {
     ///
     /// # Panics
     ///
-    /// If `min_timestamp` > `max_timestamp`.
+    /// If `min_timestamp` > `max_timestamp`, or if the commit can't be parsed
+    /// byte-for-byte with [`RawCommit::parse`].
     #[instrument(level = "debug", skip_all)]
     #[must_use]
     fn brute_force_timestamps(
@@ -437,129 +807,175 @@ pub trait CommitExt<'repo>: Borrow<Commit<'repo>> + Debug {
         min_timestamp: impl Into<Option<i64>>,
         max_timestamp: impl Into<Option<i64>>,
     ) -> BruteForcedCommit<'repo> {
-        let commit = self.borrow();
-        let min_timestamp = min_timestamp
-            .into()
-            .unwrap_or_else(|| commit.author().when().seconds());
-
-        // TODO: actually short-circuit on full matches so this isn't always an infinite
-        // loop
-        let max_timestamp = max_timestamp.into().unwrap_or(i64::MAX);
-
-        let base_commit = String::from_utf8(self.to_bytes()).unwrap();
-
-        let base_commit_lines = base_commit.split('\n').collect::<Vec<&str>>();
-        let author_line_index = base_commit_lines
-            .iter()
-            .position(|line| line.starts_with("author "))
-            .expect("author line missing in commit");
-        let author_line_pieces = &base_commit_lines[author_line_index]
-            .split(' ')
-            .collect::<Vec<_>>();
-        let committer_line_index = base_commit_lines
-            .iter()
-            .position(|line| line.starts_with("committer "))
-            .expect("committer line missing in commit");
-        let committer_line_pieces = &base_commit_lines[committer_line_index]
-            .split(' ')
-            .collect::<Vec<_>>();
+        let commit = RawCommit::from_commit(self.borrow()).expect("failed to parse commit");
+        brute_force_timestamps(
+            &commit,
+            repo,
+            target_prefix,
+            min_timestamp.into(),
+            max_timestamp.into(),
+            None,
+            None,
+            true,
+        )
+        .expect("failed to write brute-forced commit")
+    }
 
-        let commit_create_buffer = |author_timestamp: i64, committer_timestamp: i64| {
-            let mut commit_lines = base_commit_lines.clone();
+    /// Like [`CommitExt::brute_force_timestamps`], but every candidate is
+    /// signed by `signer`, so the resulting commit ID accounts for its
+    /// `gpgsig` header.
+    ///
+    /// Because a signature covers everything in the commit except the
+    /// signature itself, each candidate's timestamps are chosen first, then the
+    /// unsigned candidate is signed, and finally the signed object is hashed.
+    /// Signing is much slower than hashing, so only the first
+    /// [`SIGNED_WINDOW`][search::SIGNED_WINDOW] seconds of the range are
+    /// searched, and the ID usually won't match much of `target_prefix`.
+    ///
+    /// # Errors
+    ///
+    /// If the commit can't be parsed, or signing any candidate fails.
+    ///
+    /// # Panics
+    ///
+    /// If `min_timestamp` > `max_timestamp`.
+    #[instrument(level = "debug", skip_all)]
+    fn brute_force_timestamps_signed(
+        &self,
+        repo: &'repo Repository,
+        target_prefix: &[u8],
+        min_timestamp: impl Into<Option<i64>>,
+        max_timestamp: impl Into<Option<i64>>,
+        signer: &CommitSigner,
+    ) -> Result<BruteForcedCommit<'repo>> {
+        brute_force_timestamps(
+            &RawCommit::from_commit(self.borrow())?,
+            repo,
+            target_prefix,
+            min_timestamp.into(),
+            max_timestamp.into(),
+            Some(signer),
+            None,
+            false,
+        )
+    }
+}
 
-            let mut author_line_pieces = author_line_pieces.clone();
-            let i = author_line_pieces.len() - 2;
-            let author_timestamp = author_timestamp.to_string();
-            author_line_pieces[i] = &author_timestamp;
-            let author_line = author_line_pieces.join(" ");
-            commit_lines[author_line_index] = &author_line;
+/// Implementation of [`CommitExt::brute_force_timestamps`],
+/// [`CommitExt::brute_force_timestamps_signed`], and
+/// [`RepositoryExt::brute_force_commit`].
+///
+/// Candidates only exist in memory; the winner is the only object written.
+///
+/// With `checkpoints`, unsigned searches save a [`Checkpoint`] in
+/// `.git/save/` as they go. If one is found there from an interrupted search
+/// of the same commit (ignoring its timestamps) for the same target, we resume
+/// that search, with its timestamp range instead of the one requested.
+///
+/// With `widening`, only some of the target must match for the result to be
+/// complete, and the window is widened until it does, as described there.
+///
+/// Signed searches are limited to the first [`search::SIGNED_WINDOW`]
+/// timestamps and never widened, so their IDs usually won't match much of
+/// the target.
+#[allow(clippy::too_many_arguments)]
+fn brute_force_timestamps<'repo>(
+    base_commit: &RawCommit,
+    repo: &'repo Repository,
+    target_prefix: &[u8],
+    min_timestamp: Option<i64>,
+    max_timestamp: Option<i64>,
+    signer: Option<&CommitSigner>,
+    widening: Option<Widening>,
+    checkpoints: bool,
+) -> Result<BruteForcedCommit<'repo>> {
+    let mut min_timestamp = min_timestamp.unwrap_or(base_commit.author.seconds);
+    let requested_timestamp = min_timestamp;
+
+    // TODO: actually short-circuit on full matches so this isn't always an infinite
+    // loop
+    let mut max_timestamp = max_timestamp.unwrap_or(i64::MAX);
+
+    // Signatures may not be reproducible, so we can't resume signed searches.
+    let checkpoints_dir = repo.path().join("save");
+    let checkpoints = if checkpoints && signer.is_none() {
+        Some(checkpoints_dir.as_path())
+    } else {
+        None
+    };
+
+    if let Some(dir) = checkpoints {
+        if let Some(checkpoint) = Checkpoint::load(dir, base_commit, target_prefix)? {
+            if checkpoint.shard == Shard::ALL {
+                min_timestamp = checkpoint.min_timestamp;
+                max_timestamp = checkpoint.max_timestamp;
+            }
+        }
+    }
 
-            let mut committer_line_pieces = committer_line_pieces.clone();
-            let i = committer_line_pieces.len() - 2;
-            let committer_timestamp = committer_timestamp.to_string();
-            committer_line_pieces[i] = &committer_timestamp;
-            let committer_line = committer_line_pieces.join(" ");
-            commit_lines[committer_line_index] = &committer_line;
+    let required_nibbles = widening.map_or(target_prefix.len() * 2, |widening| {
+        widening.required_nibbles
+    });
+
+    // Every signed candidate runs the signing program, so we only try a few.
+    let widening = if signer.is_some() {
+        let capped = min_timestamp.saturating_add(search::SIGNED_WINDOW - 1);
+        if max_timestamp > capped {
+            debug!(
+                "Only searching {} seconds for a signed commit.",
+                search::SIGNED_WINDOW
+            );
+            max_timestamp = capped;
+        }
+        None
+    } else {
+        widening
+    };
+    let start = Instant::now();
+    let mut candidates = 0_u64;
+    let mut previous_window = 0;
+    loop {
+        let step = Instant::now();
+        let best = search::best_candidate(
+            base_commit,
+            target_prefix,
+            min_timestamp,
+            max_timestamp,
+            signer,
+            Shard::ALL,
+            checkpoints,
+        )?
+        .expect("min_timestamp > max_timestamp");
+
+        // a wider window includes every candidate in the previous one
+        let window = max_timestamp - min_timestamp + 1;
+        candidates = candidates.saturating_add(
+            search::candidates_in_window(window) - search::candidates_in_window(previous_window),
+        );
+        previous_window = window;
+        let quality = best.quality(target_prefix, candidates, requested_timestamp);
 
-            commit_lines.join("\n")
+        let widening = match widening {
+            Some(widening) if quality.matching_nibbles < required_nibbles => widening,
+            _ => return best.write(repo, quality, required_nibbles),
         };
 
-        let (_best_score, best_committer_timestamp, best_author_timestamp, best_oid, best_body) =
-            ((min_timestamp..=max_timestamp)
-                .into_par_iter()
-                .map(|author_timestamp| {
-                    (author_timestamp..=max_timestamp)
-                        .into_par_iter()
-                        .map(|committer_timestamp| {
-                            let candidate_body =
-                                commit_create_buffer(author_timestamp, committer_timestamp);
-                            let candidate_oid = Oid::for_object("commit", candidate_body.as_ref());
-
-                            let score = candidate_oid
-                                .as_bytes()
-                                .iter()
-                                .zip(target_prefix.iter())
-                                .map(|(a, b)| (a ^ b))
-                                .collect::<Vec<u8>>();
-
-                            (
-                                score,
-                                committer_timestamp,
-                                author_timestamp,
-                                candidate_oid,
-                                candidate_body,
-                            )
-                        })
-                        .min()
-                        .unwrap()
-                }))
-            .min()
-            .unwrap();
-
-        let brute_forced_commit_oid = commit
-            .amend(
-                None,
-                Signature::new(
-                    commit.author().name().unwrap(),
-                    commit.author().email().unwrap(),
-                    &git2::Time::new(
-                        best_author_timestamp,
-                        commit.author().when().offset_minutes(),
-                    ),
-                )
-                .as_ref()
-                .ok(),
-                Signature::new(
-                    commit.committer().name().unwrap(),
-                    commit.committer().email().unwrap(),
-                    &git2::Time::new(
-                        best_committer_timestamp,
-                        commit.committer().when().offset_minutes(),
-                    ),
-                )
-                .as_ref()
-                .ok(),
-                None,
-                None,
-                None,
-            )
-            .unwrap();
-        assert_eq!(best_oid, brute_forced_commit_oid);
-
-        let brute_forced_commit = repo.find_commit(brute_forced_commit_oid).unwrap();
-        assert_eq!(best_body.as_bytes(), brute_forced_commit.to_bytes());
-
-        if best_oid.as_bytes().starts_with(target_prefix) {
-            debug!("Brute-forced a complete prefix match: {best_oid} for {target_prefix:02x?}");
-            BruteForcedCommit::Complete {
-                commit: brute_forced_commit,
-            }
-        } else {
-            debug!("Brute-forced a partial prefix match: {best_oid} for {target_prefix:02x?}");
-            BruteForcedCommit::Incomplete {
-                commit: brute_forced_commit,
-            }
+        // the next window has about four times as many candidates
+        if start.elapsed() + step.elapsed() * 4 > widening.budget {
+            info!(
+                "Out of time to widen the search beyond {} seconds.",
+                window.separate_with_underscores()
+            );
+            return best.write(repo, quality, required_nibbles);
         }
+
+        max_timestamp = min_timestamp.saturating_add(window.saturating_mul(2)) - 1;
+        info!(
+            "Best candidate only matched {} of {} hex digits; widening the search to {} seconds.",
+            quality.matching_nibbles,
+            required_nibbles,
+            (window * 2).separate_with_underscores()
+        );
     }
 }
 
@@ -575,14 +991,39 @@ pub enum BruteForcedCommit<'repo> {
     Complete {
         /// The resulting commit.
         commit: Commit<'repo>,
+        /// How well it matched, and what it took.
+        quality: MatchQuality,
     },
     /// The specified `target_prefix` was not entirely matched.
     Incomplete {
         /// The resulting commit.
         commit: Commit<'repo>,
+        /// How well it matched, and what it took.
+        quality: MatchQuality,
     },
 }
 
+/// How well a [`BruteForcedCommit`] matched its target prefix, and what it
+/// took to find it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MatchQuality {
+    /// The number of leading bits of the commit ID that match the target.
+    pub matching_bits: u32,
+    /// The number of leading hex digits of the commit ID that match the
+    /// target.
+    pub matching_nibbles: usize,
+    /// The number of candidates searched, including any searched before
+    /// resuming from a checkpoint.
+    pub candidates: u64,
+    /// The chosen author timestamp.
+    pub author_timestamp: i64,
+    /// The chosen committer timestamp.
+    pub committer_timestamp: i64,
+    /// How many seconds the committer timestamp is after the requested
+    /// (minimum) timestamp.
+    pub offset_seconds: i64,
+}
+
 impl<'repo> Borrow<Commit<'repo>> for BruteForcedCommit<'repo> {
     fn borrow(&self) -> &Commit<'repo> {
         self.commit()
@@ -608,6 +1049,15 @@ impl<'repo> BruteForcedCommit<'repo> {
         }
     }
 
+    /// Returns how well the commit matched its target prefix.
+    #[must_use]
+    pub const fn quality(&self) -> &MatchQuality {
+        match self {
+            BruteForcedCommit::Complete { quality, .. }
+            | BruteForcedCommit::Incomplete { quality, .. } => quality,
+        }
+    }
+
     /// Returns a reference to the underlying [`Commit`] if it is a complete
     /// match.
     #[must_use]
@@ -629,6 +1079,181 @@ impl<'repo> BruteForcedCommit<'repo> {
     }
 }
 
+impl fmt::Display for MatchQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} hex digits ({} bits) matched after {} candidates, authored {:+}s and committed \
+             {:+}s from the requested time",
+            self.matching_nibbles,
+            self.matching_bits,
+            self.candidates.separate_with_underscores(),
+            self.offset_seconds - (self.committer_timestamp - self.author_timestamp),
+            self.offset_seconds,
+        )
+    }
+}
+
+/// Implementation of [`RepositoryExt::brute_force_tag`] and
+/// [`TagExt::brute_force_timestamp`].
+///
+/// Unlike commit searches, each wider step only searches the candidates that
+/// weren't in the last, since tags only have one timestamp to vary.
+fn brute_force_tag<'repo>(
+    base_tag: &RawTag,
+    repo: &'repo Repository,
+    target_prefix: &[u8],
+    min_timestamp: i64,
+    max_timestamp: i64,
+    nonce: bool,
+    widening: Widening,
+) -> Result<BruteForcedTag<'repo>> {
+    assert!(
+        min_timestamp <= max_timestamp,
+        "min_timestamp > max_timestamp"
+    );
+    if base_tag.tagger.is_none() {
+        bail!("Can't brute force a tag without a tagger.");
+    }
+    if base_tag.is_signed() {
+        bail!("Can't brute force a signed tag without invalidating its signature.");
+    }
+
+    let mut timestamps = min_timestamp..=max_timestamp;
+    let mut nonces = 0..=0;
+
+    let start = Instant::now();
+    let mut best = None;
+    let mut candidates = 0_u64;
+    loop {
+        let steps = u64::try_from(*timestamps.end() - *timestamps.start() + 1)?
+            .saturating_mul(nonces.end() - nonces.start() + 1);
+        let step_best =
+            search::best_tag_candidate(base_tag, target_prefix, timestamps.clone(), nonces.clone());
+        best = best.into_iter().chain(step_best).min();
+        candidates = candidates.saturating_add(steps);
+
+        let candidate = best.take().expect("there's always a candidate");
+        let quality = candidate.quality(target_prefix, candidates, min_timestamp);
+        // the next step is as large as everything so far
+        if quality.matching_nibbles >= widening.required_nibbles
+            || start.elapsed() * 2 >= widening.budget
+        {
+            return candidate.write(repo, quality, widening.required_nibbles);
+        }
+        best = Some(candidate);
+
+        if nonce {
+            let searched = *nonces.end() + 1;
+            nonces = searched..=searched.saturating_mul(2) - 1;
+        } else {
+            let searched = *timestamps.end() - min_timestamp + 1;
+            timestamps = *timestamps.end() + 1..=min_timestamp.saturating_add(searched * 2) - 1;
+        }
+        info!(
+            "Best tag candidate only matched {} of {} hex digits; searching {} more candidates.",
+            quality.matching_nibbles,
+            widening.required_nibbles,
+            candidates.separate_with_underscores()
+        );
+    }
+}
+
+/// The tag resulting from a [`RepositoryExt::brute_force_tag`] call,
+/// wrapped to indicate whether the target prefix was complete or incompletely
+/// matched.
+#[derive(Debug, Clone)]
+#[must_use]
+pub enum BruteForcedTag<'repo> {
+    /// The required part of the target prefix was entirely matched.
+    Complete {
+        /// The resulting tag.
+        tag: Tag<'repo>,
+        /// How well it matched, and what it took.
+        quality: MatchQuality,
+    },
+    /// The required part of the target prefix was not entirely matched.
+    Incomplete {
+        /// The resulting tag.
+        tag: Tag<'repo>,
+        /// How well it matched, and what it took.
+        quality: MatchQuality,
+    },
+}
+
+impl<'repo> BruteForcedTag<'repo> {
+    /// Returns a reference to the underlying [`Tag`].
+    #[must_use]
+    pub const fn tag(&self) -> &Tag<'repo> {
+        match self {
+            BruteForcedTag::Complete { tag, .. } | BruteForcedTag::Incomplete { tag, .. } => tag,
+        }
+    }
+
+    /// Returns how well the tag matched its target prefix.
+    #[must_use]
+    pub const fn quality(&self) -> &MatchQuality {
+        match self {
+            BruteForcedTag::Complete { quality, .. }
+            | BruteForcedTag::Incomplete { quality, .. } => quality,
+        }
+    }
+}
+
+/// Extension methods for [`Tag`].
+pub trait TagExt<'repo>: Borrow<Tag<'repo>> + Debug {
+    /// Returns the raw contents of the underlying Git tag object.
+    ///
+    /// libgit2 doesn't expose these for a parsed [`Tag`], so they're read
+    /// from `repo`'s object database.
+    ///
+    /// # Errors
+    ///
+    /// If the tag can't be read from `repo`.
+    fn to_bytes(&self, repo: &Repository) -> Result<Vec<u8>> {
+        let tag: &Tag = self.borrow();
+        Ok(repo.odb()?.read(tag.id())?.data().to_vec())
+    }
+
+    /// Creates a new version of this annotated tag whose ID starts with
+    /// `target_prefix`, varying only its tagger timestamp, from
+    /// `min_timestamp` to `max_timestamp`, like
+    /// [`CommitExt::brute_force_timestamps`]. The tag's reference isn't
+    /// updated.
+    ///
+    /// # Errors
+    ///
+    /// If the tag can't be read, has no tagger, is signed, or if the new tag
+    /// can't be written.
+    ///
+    /// # Panics
+    ///
+    /// If `min_timestamp` > `max_timestamp`.
+    #[instrument(level = "debug", skip_all)]
+    fn brute_force_timestamp(
+        &self,
+        repo: &'repo Repository,
+        target_prefix: &[u8],
+        min_timestamp: i64,
+        max_timestamp: i64,
+    ) -> Result<BruteForcedTag<'repo>> {
+        brute_force_tag(
+            &RawTag::from_tag(self.borrow(), repo)?,
+            repo,
+            target_prefix,
+            min_timestamp,
+            max_timestamp,
+            false,
+            Widening {
+                required_nibbles: target_prefix.len() * 2,
+                budget: Duration::ZERO,
+            },
+        )
+    }
+}
+
+impl<'repo, T> TagExt<'repo> for T where T: Borrow<Tag<'repo>> + Debug {}
+
 /// Extension methods for [`Oid`].
 pub trait OidExt: Borrow<Oid> + Debug {
     /// This is similar to [`Oid::from_bytes`], but faster.
@@ -648,16 +1273,13 @@ pub trait OidExt: Borrow<Oid> + Debug {
     }
 
     /// This is similar to [`Oid::hash_object`], but potentially faster.
+    ///
+    /// [`Oid`] can only hold SHA-1 object IDs; see
+    /// [`ObjectFormat::hash_object`][crate::hash::ObjectFormat::hash_object]
+    /// for other formats.
     #[must_use]
     fn for_object(object_type: &'static str, body: &[u8]) -> Oid {
-        let oid: GenericArray<u8, U20> = sha1::Sha1::new()
-            .chain_update(object_type)
-            .chain_update(" ")
-            .chain_update(body.len().to_string())
-            .chain_update([0x00])
-            .chain_update(&body)
-            .finalize();
-        let oid: [u8; 20] = oid.into();
+        let oid = sha1_object(object_type, body);
         let oid = Oid::from_array(oid);
         if cfg!(debug_assertions) {
             // cross-check with git2
@@ -667,6 +1289,200 @@ pub trait OidExt: Borrow<Oid> + Debug {
         }
         oid
     }
+
+    /// Like [`OidExt::for_object`], but hashes a batch of objects at once,
+    /// using the fastest [`Sha1Backend`] supported by the current CPU. This is
+    /// much faster for many small objects of similar lengths, like brute force
+    /// candidates, but doesn't detect SHA-1 collision attacks.
+    #[must_use]
+    fn for_objects<B: AsRef<[u8]>>(object_type: &'static str, bodies: &[B]) -> Vec<Oid> {
+        let oids = Sha1Backend::detect()
+            .hash_objects(object_type, bodies)
+            .into_iter()
+            .map(Oid::from_array)
+            .collect::<Vec<_>>();
+        if cfg!(debug_assertions) {
+            // cross-check with git2
+            for (body, oid) in bodies.iter().zip(&oids) {
+                let expected =
+                    Oid::hash_object(ObjectType::from_str(object_type).unwrap(), body.as_ref())
+                        .unwrap();
+                assert_eq!(&expected, oid);
+            }
+        }
+        oids
+    }
 }
 
 impl<T> OidExt for T where T: Borrow<Oid> + Debug {}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        git2::{TreeWalkMode, TreeWalkResult},
+    };
+
+    /// Creates a repository with `paths` committed with the content `old`,
+    /// and then changed to `new` in the working tree.
+    fn changed_repository(paths: &[&str]) -> Result<TemporaryRepository> {
+        let repo = Repository::temporary()?;
+        let workdir = repo.workdir().unwrap().to_path_buf();
+        for path in paths {
+            fs::create_dir_all(workdir.join(path).parent().unwrap())?;
+            fs::write(workdir.join(path), "old")?;
+        }
+
+        let mut index = repo.index()?;
+        index.add_all(["*"], Default::default(), None)?;
+        index.write()?;
+        let tree = repo.find_tree(index.write_tree()?)?;
+        let signature = Signature::now("save", "save@example.com")?;
+        repo.commit(Some("HEAD"), &signature, &signature, "r1", &tree, &[])?;
+        drop(tree);
+
+        for path in paths {
+            fs::write(workdir.join(path), "new")?;
+        }
+        Ok(repo)
+    }
+
+    /// Returns the content of every file in the tree that `pathspecs` would
+    /// save, by path.
+    fn saved_with(repo: &Repository, pathspecs: &[&str]) -> Result<HashMap<String, String>> {
+        let tree = repo
+            .working_index(pathspecs, &Guardrails::disabled())?
+            .write_tree()?;
+        let mut files = HashMap::new();
+        repo.find_tree(tree)?
+            .walk(TreeWalkMode::PreOrder, |dir, entry| {
+                if let Ok(blob) = entry
+                    .to_object(repo)
+                    .and_then(|object| object.peel_to_blob())
+                {
+                    let path = format!("{}{}", dir, entry.name().unwrap());
+                    let content = String::from_utf8_lossy(blob.content()).into_owned();
+                    files.insert(path, content);
+                }
+                TreeWalkResult::Ok
+            })?;
+        Ok(files)
+    }
+
+    /// Returns which of `paths` have the content `new` in `files`.
+    fn updated<'a>(files: &HashMap<String, String>, paths: &[&'a str]) -> Vec<&'a str> {
+        paths
+            .iter()
+            .copied()
+            .filter(|path| files.get(*path).map(String::as_str) == Some("new"))
+            .collect()
+    }
+
+    #[test]
+    fn pathspecs_limit_what_is_saved() -> Result<()> {
+        let paths = ["README", "docs/guide.md", "src/lib.rs", "src/notes.md"];
+        let repo = changed_repository(&paths)?;
+        fs::write(repo.workdir().unwrap().join("untracked.md"), "new")?;
+
+        let files = saved_with(&repo, &[])?;
+        assert_eq!(updated(&files, &paths), paths);
+        assert!(files.contains_key("untracked.md"));
+
+        let files = saved_with(&repo, &["src"])?;
+        assert_eq!(updated(&files, &paths), ["src/lib.rs", "src/notes.md"]);
+        assert_eq!(files["README"], "old");
+        assert!(!files.contains_key("untracked.md"));
+
+        let files = saved_with(&repo, &["*.md"])?;
+        assert_eq!(updated(&files, &paths), ["docs/guide.md", "src/notes.md"]);
+        assert_eq!(files["src/lib.rs"], "old");
+        assert!(files.contains_key("untracked.md"));
+
+        let files = saved_with(&repo, &[":(exclude)src"])?;
+        assert_eq!(updated(&files, &paths), ["README", "docs/guide.md"]);
+        assert_eq!(files["src/lib.rs"], "old");
+
+        let files = saved_with(&repo, &["src", ":!*.md"])?;
+        assert_eq!(updated(&files, &paths), ["src/lib.rs"]);
+        assert_eq!(files["src/notes.md"], "old");
+
+        Ok(())
+    }
+
+    #[test]
+    fn pathspecs_are_converted_for_libgit2() {
+        assert_eq!(libgit2_pathspecs(&["src", "*.md"]), ["src", "*.md"]);
+        assert_eq!(libgit2_pathspecs(&[":(exclude)src"]), ["!src", "*"]);
+        assert_eq!(
+            libgit2_pathspecs(&["src", ":^*.md", ":!docs"]),
+            ["!*.md", "!docs", "src"]
+        );
+    }
+
+    #[test]
+    fn committer_searches_keep_the_author_date() -> Result<()> {
+        let repo = Repository::temporary()?;
+        let author = Signature::new("A", "a@example.com", &Time::new(1_500_000_000, 60))?;
+        let committer = Signature::new("C", "c@example.com", &Time::new(1_600_000_000, 0))?;
+        let base = RawCommit::new(
+            Oid::for_object("tree", &[]),
+            &[],
+            &author,
+            &committer,
+            "Rewritten\n",
+        );
+
+        let commit = repo.brute_force_committer_timestamp(
+            &base,
+            &[0xAB, 0xCD],
+            1_600_000_000,
+            256,
+            Widening {
+                required_nibbles: 2,
+                budget: Duration::from_secs(60),
+            },
+        )?;
+        let quality = *commit.quality();
+        let commit = commit.commit();
+        assert!(quality.matching_nibbles >= 2);
+        assert_eq!(commit.author().when(), author.when());
+        assert_eq!(quality.author_timestamp, 1_500_000_000);
+        assert!(commit.time().seconds() >= 1_600_000_000);
+        assert_eq!(commit.time().seconds(), quality.committer_timestamp);
+        assert!(commit.id().to_string().starts_with("ab"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn tag_searches_without_a_budget_dont_widen() -> Result<()> {
+        let repo = Repository::temporary()?;
+        let tagger = Signature::new("T", "t@example.com", &Time::new(1_600_000_000, 0))?;
+        let base = RawTag::new(
+            Oid::for_object("commit", b"target"),
+            ObjectType::Commit,
+            "v1.0",
+            &tagger,
+            "Release\n",
+        );
+
+        // a full match is practically impossible, so only the budget stops it
+        for nonce in [false, true] {
+            let tag = repo.brute_force_tag(
+                &base,
+                &[0; 20],
+                1_600_000_000,
+                1_600_000_063,
+                nonce,
+                Widening {
+                    required_nibbles: 40,
+                    budget: Duration::ZERO,
+                },
+            )?;
+            assert!(matches!(tag, BruteForcedTag::Incomplete { .. }));
+            assert_eq!(tag.quality().candidates, 64);
+        }
+
+        Ok(())
+    }
+}
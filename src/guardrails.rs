@@ -0,0 +1,551 @@
+//! Guarding against committing files that are probably mistakes, such as
+//! huge build artifacts.
+
+use {
+    eyre::{bail, Result},
+    git2::{Config, ErrorCode},
+    std::{
+        fmt::{self, Display},
+        fs::File,
+        io::Read,
+        path::Path,
+    },
+    tracing::{debug, instrument},
+};
+
+/// Files larger than this are rejected by default, matching the size at which
+/// GitHub starts warning about large files.
+const DEFAULT_MAX_FILE_SIZE: u64 = 50 * 1024 * 1024;
+
+/// Paths that are rejected by default, because they're almost always build
+/// output.
+///
+/// Patterns ending in `/` match directories, and other patterns match files.
+/// Patterns with no other `/` match names at any depth, with `*` and `?` as
+/// wildcards. Patterns containing a `/`, such as a leading one, match the
+/// whole path from the root of the working tree, like in `.gitignore`: `*` and
+/// `?` don't match `/`, but `**` matches any number of directories.
+const DEFAULT_ARTIFACT_PATTERNS: &[&str] = &[
+    "*.o",
+    "*.obj",
+    "*.a",
+    "*.lib",
+    "*.so",
+    "*.dylib",
+    "*.dll",
+    "*.exe",
+    "*.class",
+    "*.pyc",
+    "*.pyo",
+    "core",
+    "node_modules/",
+    "__pycache__/",
+];
+
+/// How many bytes at the start of a file we check to decide if it's binary,
+/// the same number Git uses.
+const BINARY_SNIFF_LENGTH: usize = 8000;
+
+/// Checks that are applied to each new or modified file before it's added to
+/// the index.
+///
+/// These are configured in Git with `save.maxFileSize` (`0` to disable),
+/// `save.rejectBinary`, `save.artifactPattern` (may be repeated, replacing the
+/// defaults), and `save.guardAction` (`abort` or `skip`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Guardrails {
+    /// Files larger than this many bytes are rejected.
+    pub max_file_size: Option<u64>,
+    /// Whether files that look binary are rejected.
+    pub reject_binary: bool,
+    /// Paths matching these patterns are rejected.
+    pub artifact_patterns: Vec<String>,
+    /// What happens to rejected files.
+    pub action: GuardAction,
+}
+
+/// What to do when [`Guardrails`] reject some files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardAction {
+    /// Fail without committing anything.
+    Abort,
+    /// Leave the rejected files out of the commit, with a warning logged.
+    Skip,
+}
+
+/// Why a file was rejected by [`Guardrails`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// The file is larger than [`Guardrails::max_file_size`].
+    TooLarge {
+        /// The size of the file, in bytes.
+        size: u64,
+        /// The configured limit, in bytes.
+        limit: u64,
+    },
+    /// The file looks binary.
+    Binary,
+    /// The path matches one of [`Guardrails::artifact_patterns`].
+    Artifact {
+        /// The matching pattern.
+        pattern: String,
+    },
+}
+
+impl Default for Guardrails {
+    fn default() -> Self {
+        Self {
+            max_file_size: Some(DEFAULT_MAX_FILE_SIZE),
+            reject_binary: false,
+            artifact_patterns: DEFAULT_ARTIFACT_PATTERNS
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            action: GuardAction::Abort,
+        }
+    }
+}
+
+impl Guardrails {
+    /// Returns [`Guardrails`] that don't reject anything.
+    #[must_use]
+    pub const fn disabled() -> Self {
+        Self {
+            max_file_size: None,
+            reject_binary: false,
+            artifact_patterns: vec![],
+            action: GuardAction::Abort,
+        }
+    }
+
+    /// Loads [`Guardrails`] from Git configuration, falling back to the
+    /// defaults for anything that isn't configured.
+    ///
+    /// # Errors
+    ///
+    /// If any of the configured values are invalid.
+    #[instrument(level = "debug", skip_all)]
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let mut guardrails = Self::default();
+
+        match config.get_i64("save.maxFileSize") {
+            Ok(size) if size <= 0 => guardrails.max_file_size = None,
+            Ok(size) => guardrails.max_file_size = Some(size.unsigned_abs()),
+            Err(err) if err.code() == ErrorCode::NotFound => {},
+            Err(err) => return Err(err.into()),
+        }
+
+        match config.get_bool("save.rejectBinary") {
+            Ok(reject_binary) => guardrails.reject_binary = reject_binary,
+            Err(err) if err.code() == ErrorCode::NotFound => {},
+            Err(err) => return Err(err.into()),
+        }
+
+        let mut patterns = vec![];
+        for entry in &config.multivar("save.artifactPattern", None)? {
+            if let Some(value) = entry?.value() {
+                patterns.push(value.to_string());
+            }
+        }
+        if !patterns.is_empty() {
+            guardrails.artifact_patterns = patterns;
+        }
+
+        match config.get_string("save.guardAction") {
+            Ok(action) => {
+                guardrails.action = match action.as_str() {
+                    "abort" => GuardAction::Abort,
+                    "skip" => GuardAction::Skip,
+                    other => bail!("Invalid save.guardAction: {:?}", other),
+                }
+            },
+            Err(err) if err.code() == ErrorCode::NotFound => {},
+            Err(err) => return Err(err.into()),
+        }
+
+        debug!("Using guardrails: {:?}", guardrails);
+        Ok(guardrails)
+    }
+
+    /// Checks the file at `path`, relative to the working tree at `workdir`.
+    #[must_use]
+    pub fn check(&self, workdir: &Path, path: &Path) -> Option<Violation> {
+        let path_string = path.to_string_lossy();
+        for pattern in &self.artifact_patterns {
+            if matches_artifact_pattern(pattern, &path_string) {
+                return Some(Violation::Artifact {
+                    pattern: pattern.clone(),
+                });
+            }
+        }
+
+        let full_path = workdir.join(path);
+        let metadata = full_path.symlink_metadata().ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+
+        if let Some(limit) = self.max_file_size {
+            if metadata.len() > limit {
+                return Some(Violation::TooLarge {
+                    size: metadata.len(),
+                    limit,
+                });
+            }
+        }
+
+        if self.reject_binary && looks_binary(&full_path) {
+            return Some(Violation::Binary);
+        }
+
+        None
+    }
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge { size, limit } => write!(
+                f,
+                "{} is larger than the limit of {}",
+                human_size(*size),
+                human_size(*limit)
+            ),
+            Self::Binary => write!(f, "looks like a binary file"),
+            Self::Artifact { pattern } => write!(f, "matches artifact pattern {:?}", pattern),
+        }
+    }
+}
+
+/// Whether a file contains a NUL byte near its start, the same heuristic Git
+/// uses to detect binary files.
+fn looks_binary(path: &Path) -> bool {
+    let mut buffer = Vec::with_capacity(BINARY_SNIFF_LENGTH);
+    match File::open(path) {
+        Ok(file) => {
+            if file
+                .take(BINARY_SNIFF_LENGTH as u64)
+                .read_to_end(&mut buffer)
+                .is_err()
+            {
+                return false;
+            }
+        },
+        Err(_) => return false,
+    }
+    buffer.contains(&0)
+}
+
+/// Whether `path` (with `/` separators) matches an artifact `pattern`. See
+/// [`DEFAULT_ARTIFACT_PATTERNS`] for the syntax.
+fn matches_artifact_pattern(pattern: &str, path: &str) -> bool {
+    let (pattern, directory) = match pattern.strip_suffix('/') {
+        Some(directory) => (directory, true),
+        None => (pattern, false),
+    };
+    let components = path.split('/').collect::<Vec<_>>();
+    let (name, directories) = components.split_last().unwrap_or((&"", &[]));
+
+    if !pattern.contains('/') {
+        return if directory {
+            directories
+                .iter()
+                .any(|component| glob_matches(pattern, component))
+        } else {
+            glob_matches(pattern, name)
+        };
+    }
+
+    let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+    if directory {
+        (1..=directories.len())
+            .any(|depth| path_glob_matches(pattern, &directories[..depth].join("/")))
+    } else {
+        path_glob_matches(pattern, path)
+    }
+}
+
+/// Matches a whole `path` against a `pattern` where `*` and `?` match
+/// anything but `/`, and `**` matches anything, including no directories at
+/// all when it's followed by a `/`.
+fn path_glob_matches(pattern: &str, path: &str) -> bool {
+    fn matches(pattern: &[char], path: &[char]) -> bool {
+        match pattern {
+            [] => path.is_empty(),
+            ['*', '*', rest @ ..] => {
+                if let ['/', after @ ..] = rest {
+                    if matches(after, path) {
+                        return true;
+                    }
+                }
+                (0..=path.len()).any(|i| matches(rest, &path[i..]))
+            },
+            ['*', rest @ ..] => (0..=path.len())
+                .take_while(|&i| i == 0 || path[i - 1] != '/')
+                .any(|i| matches(rest, &path[i..])),
+            ['?', rest @ ..] => match path {
+                [c, after @ ..] if *c != '/' => matches(rest, after),
+                _ => false,
+            },
+            [c, rest @ ..] => match path {
+                [d, after @ ..] if c == d => matches(rest, after),
+                _ => false,
+            },
+        }
+    }
+
+    matches(
+        &pattern.chars().collect::<Vec<_>>(),
+        &path.chars().collect::<Vec<_>>(),
+    )
+}
+
+/// Matches `text` against a `pattern` where `*` matches any sequence of
+/// characters and `?` matches any single character.
+pub(crate) fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match backtrack {
+                Some((star, star_t)) => {
+                    p = star + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star, star_t + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Formats a number of bytes for humans, like `1.5 GiB`.
+#[allow(clippy::cast_precision_loss)]
+fn human_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[unit])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::git2::{RepositoryExt, TemporaryRepository},
+        git2::Repository,
+        std::fs,
+    };
+
+    /// Creates a repository with `files` in its working tree.
+    fn repository(files: &[(&str, &[u8])]) -> Result<TemporaryRepository> {
+        let repo = Repository::temporary()?;
+        let workdir = repo.workdir().unwrap().to_path_buf();
+        for (path, content) in files {
+            fs::create_dir_all(workdir.join(path).parent().unwrap())?;
+            fs::write(workdir.join(path), content)?;
+        }
+        Ok(repo)
+    }
+
+    /// The paths in the index, sorted.
+    fn indexed(index: &git2::Index) -> Vec<String> {
+        index
+            .iter()
+            .map(|entry| String::from_utf8_lossy(&entry.path).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn rejects_large_files() -> Result<()> {
+        let repo = repository(&[("small", b"1234"), ("large", b"12345")])?;
+        let workdir = repo.workdir().unwrap();
+        let guardrails = Guardrails {
+            max_file_size: Some(4),
+            ..Guardrails::disabled()
+        };
+
+        assert_eq!(guardrails.check(workdir, Path::new("small")), None);
+        assert_eq!(
+            guardrails.check(workdir, Path::new("large")),
+            Some(Violation::TooLarge { size: 5, limit: 4 })
+        );
+        assert_eq!(
+            Guardrails::disabled().check(workdir, Path::new("large")),
+            None
+        );
+        assert_eq!(
+            Violation::TooLarge {
+                size: 3 * 1024 * 1024 / 2,
+                limit: 1024
+            }
+            .to_string(),
+            "1.5 MiB is larger than the limit of 1.0 KiB"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn detects_binary_files() -> Result<()> {
+        let mut late_nul = vec![b'a'; BINARY_SNIFF_LENGTH];
+        late_nul.push(0);
+        let repo = repository(&[
+            ("text", b"hello\n"),
+            ("binary", b"\x7fELF\x00\x01"),
+            ("late", &late_nul),
+        ])?;
+        let workdir = repo.workdir().unwrap();
+        let guardrails = Guardrails {
+            reject_binary: true,
+            ..Guardrails::disabled()
+        };
+
+        assert_eq!(guardrails.check(workdir, Path::new("text")), None);
+        assert_eq!(
+            guardrails.check(workdir, Path::new("binary")),
+            Some(Violation::Binary)
+        );
+        // like Git, we only look at the start of the file
+        assert_eq!(guardrails.check(workdir, Path::new("late")), None);
+        assert_eq!(
+            Guardrails::default().check(workdir, Path::new("binary")),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn matches_artifact_patterns() {
+        let cases = [
+            // names at any depth
+            ("*.o", "main.o", true),
+            ("*.o", "src/lib/main.o", true),
+            ("*.o", "main.o.txt", false),
+            ("core", "src/core", true),
+            ("core", "core/lib.rs", false),
+            ("lib?.a", "liba.a", true),
+            ("lib?.a", "lib.a", false),
+            // directories at any depth
+            ("node_modules/", "node_modules/left-pad/index.js", true),
+            ("node_modules/", "web/node_modules/x.js", true),
+            ("node_modules/", "node_modules", false),
+            ("*_cache/", "a/pip_cache/b", true),
+            // a leading `/` anchors to the root
+            ("/build/", "build/out.bin", true),
+            ("/build/", "src/build/out.bin", false),
+            ("/core", "core", true),
+            ("/core", "src/core", false),
+            ("/target/*.rlib", "target/libsave.rlib", true),
+            ("/target/*.rlib", "target/debug/libsave.rlib", false),
+            // `**` spans directories
+            ("/target/**/*.rlib", "target/libsave.rlib", true),
+            ("/target/**/*.rlib", "target/debug/deps/libsave.rlib", true),
+            ("**/dist/", "dist/app.js", true),
+            ("**/dist/", "web/app/dist/app.js", true),
+            ("**/dist/", "web/distant/app.js", false),
+            ("docs/**", "docs/a/b.md", true),
+            ("docs/**", "src/docs/a.md", false),
+        ];
+        for (pattern, path, expected) in cases {
+            assert_eq!(
+                matches_artifact_pattern(pattern, path),
+                expected,
+                "{:?} matching {:?}",
+                pattern,
+                path
+            );
+        }
+
+        assert!(glob_matches("*.pem", "keys/server.pem"));
+        assert!(glob_matches("a*b*c", "aXbYc"));
+        assert!(!glob_matches("a*b*c", "aXbY"));
+    }
+
+    #[test]
+    fn aborts_or_skips() -> Result<()> {
+        let repo = repository(&[
+            ("README", b"hello"),
+            ("main.o", b"object"),
+            ("node_modules/left-pad/index.js", b"pad"),
+        ])?;
+        let mut guardrails = Guardrails::default();
+
+        let message = match repo.working_index(&[], &guardrails) {
+            Ok(index) => panic!("saved everything: {:?}", indexed(&index)),
+            Err(err) => format!("{:#}", err),
+        };
+        assert!(
+            message.contains("Refusing to save 2 file(s)"),
+            "{}",
+            message
+        );
+        assert!(message.contains("main.o: matches artifact pattern \"*.o\""));
+        assert!(message.contains("node_modules/left-pad/index.js"));
+
+        guardrails.action = GuardAction::Skip;
+        let index = repo.working_index(&[], &guardrails)?;
+        assert_eq!(indexed(&index), ["README"]);
+
+        let index = repo.working_index(&[], &Guardrails::disabled())?;
+        assert_eq!(
+            indexed(&index),
+            ["README", "main.o", "node_modules/left-pad/index.js"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_config() -> Result<()> {
+        let repo = Repository::temporary()?;
+        let mut config = repo.config()?.open_level(git2::ConfigLevel::Local)?;
+        assert_eq!(Guardrails::from_config(&config)?, Guardrails::default());
+
+        config.set_i64("save.maxFileSize", 1024)?;
+        config.set_bool("save.rejectBinary", true)?;
+        config.set_multivar("save.artifactPattern", "^$", "*.bin")?;
+        config.set_multivar("save.artifactPattern", "^$", "/out/")?;
+        config.set_str("save.guardAction", "skip")?;
+        assert_eq!(
+            Guardrails::from_config(&config)?,
+            Guardrails {
+                max_file_size: Some(1024),
+                reject_binary: true,
+                artifact_patterns: vec!["*.bin".to_string(), "/out/".to_string()],
+                action: GuardAction::Skip,
+            }
+        );
+
+        config.set_i64("save.maxFileSize", 0)?;
+        assert_eq!(Guardrails::from_config(&config)?.max_file_size, None);
+
+        config.set_str("save.guardAction", "ignore")?;
+        assert!(Guardrails::from_config(&config).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,463 @@
+//! Hashing Git objects, and detecting which object format (hash algorithm) a
+//! repository uses.
+//!
+//! Objects can be hashed in either of Git's object formats, but only SHA-1
+//! repositories can be saved to, since that's all our version of libgit2 can
+//! open; SHA-256 repositories are detected so that we can say so instead of
+//! failing to open them.
+
+use {
+    digest::{generic_array::GenericArray, Digest},
+    eyre::{bail, Result},
+    git2::{Config, ErrorCode},
+    itertools::Itertools,
+    std::path::{Path, PathBuf},
+    tracing::{debug, instrument},
+};
+
+/// A repository's object format, as configured by
+/// `extensions.objectFormat`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjectFormat {
+    /// The default: 20-byte SHA-1 object IDs.
+    Sha1,
+    /// 32-byte SHA-256 object IDs, which we can hash but not open.
+    Sha256,
+}
+
+impl Default for ObjectFormat {
+    fn default() -> Self {
+        Self::Sha1
+    }
+}
+
+impl ObjectFormat {
+    /// Returns the object format configured in a repository's configuration.
+    ///
+    /// # Errors
+    ///
+    /// If the configuration can't be read or names an unknown format.
+    pub fn from_config(config: &Config) -> Result<Self> {
+        match config.get_string("extensions.objectFormat") {
+            Ok(format) => match format.to_ascii_lowercase().as_str() {
+                "sha1" => Ok(Self::Sha1),
+                "sha256" => Ok(Self::Sha256),
+                other => bail!("Unknown extensions.objectFormat: {:?}", other),
+            },
+            Err(err) if err.code() == ErrorCode::NotFound => Ok(Self::Sha1),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Looks for a `.git` directory in `path` or any of its ancestors and
+    /// returns its object format, without opening it as a repository, since
+    /// libgit2 refuses to open repositories in formats it doesn't support.
+    ///
+    /// # Errors
+    ///
+    /// If a repository is found but its configuration can't be read.
+    #[instrument(level = "debug")]
+    pub fn discover(path: &Path) -> Result<Option<(PathBuf, Self)>> {
+        for dir in path.ancestors() {
+            let git_dir = dir.join(".git");
+            let config = git_dir.join("config");
+            if config.is_file() {
+                let format = Self::from_config(&Config::open(&config)?)?;
+                debug!("Found {:?} repository: {:?}", format, git_dir);
+                return Ok(Some((git_dir, format)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The name of this format in Git's configuration.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+        }
+    }
+
+    /// The length of an object ID in this format, in bytes.
+    #[must_use]
+    pub const fn id_length(self) -> usize {
+        match self {
+            Self::Sha1 => 20,
+            Self::Sha256 => 32,
+        }
+    }
+
+    /// Hashes a Git object of the given type (`"blob"`, `"tree"`, `"commit"`,
+    /// or `"tag"`), returning its ID.
+    #[must_use]
+    pub fn hash_object(self, object_type: &str, body: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Sha1 => sha1_object(object_type, body).to_vec(),
+            Self::Sha256 => digest_object::<sha2::Sha256>(object_type, body).to_vec(),
+        }
+    }
+}
+
+/// Hashes a Git object of the given type with any hash algorithm `D`.
+#[must_use]
+pub fn digest_object<D: Digest>(object_type: &str, body: &[u8]) -> GenericArray<u8, D::OutputSize> {
+    D::new()
+        .chain_update(object_type)
+        .chain_update(" ")
+        .chain_update(body.len().to_string())
+        .chain_update([0x00])
+        .chain_update(body)
+        .finalize()
+}
+
+/// Hashes a Git object with SHA-1.
+///
+/// With the `sha1dc` feature enabled this uses SHA-1DC, like Git and libgit2,
+/// which detects input that looks like part of a SHA-1 collision attack. Git
+/// refuses to store such objects; we log an error and return SHA-1DC's
+/// hardened hash, which won't match the ID the attacker was aiming for.
+#[must_use]
+pub fn sha1_object(object_type: &str, body: &[u8]) -> [u8; 20] {
+    #[cfg(feature = "sha1dc")]
+    {
+        let mut hasher = sha1collisiondetection::Sha1CD::default();
+        hasher.update(object_type);
+        hasher.update(" ");
+        hasher.update(body.len().to_string());
+        hasher.update([0x00]);
+        hasher.update(body);
+
+        let mut digest = Default::default();
+        if hasher.finalize_into_dirty_cd(&mut digest).is_err() {
+            tracing::error!(
+                "This {} looks like part of a SHA-1 collision attack! Git won't accept it.",
+                object_type
+            );
+        }
+        digest.into()
+    }
+
+    #[cfg(not(feature = "sha1dc"))]
+    {
+        digest_object::<sha1::Sha1>(object_type, body).into()
+    }
+}
+
+/// An implementation of SHA-1 for hashing many objects at once, with
+/// [`Sha1Backend::hash_objects`].
+///
+/// None of these detect collision attacks, even with the `sha1dc` feature
+/// enabled, so they're only meant for hashing candidates while brute forcing.
+/// Anything we actually write is hashed again by libgit2, which does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Sha1Backend {
+    /// Hashes [`LANES`] objects of the same length in lockstep, one in each
+    /// 32-bit lane of the 256-bit AVX2 registers.
+    Avx2,
+    /// Hashes one object at a time with the `sha-1` crate, which uses the x86
+    /// SHA extensions if the CPU supports them.
+    Sequential,
+}
+
+/// The number of objects hashed in lockstep by [`Sha1Backend::Avx2`].
+pub const LANES: usize = 8;
+
+impl Sha1Backend {
+    /// Every backend.
+    pub const ALL: [Self; 2] = [Self::Avx2, Self::Sequential];
+
+    /// Returns the fastest backend supported by the current CPU: hashing one
+    /// object at a time with the SHA extensions beats AVX2, which beats
+    /// hashing one object at a time without them.
+    #[must_use]
+    pub fn detect() -> Self {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if Self::Avx2.is_available() && !is_x86_feature_detected!("sha") {
+            return Self::Avx2;
+        }
+        Self::Sequential
+    }
+
+    /// Whether the current CPU supports this backend.
+    #[must_use]
+    pub fn is_available(self) -> bool {
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Self::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+            Self::Avx2 => false,
+            Self::Sequential => true,
+        }
+    }
+
+    /// Hashes Git objects of the given type, all with SHA-1, returning their
+    /// IDs in the same order. If this backend isn't available on the current
+    /// CPU, [`Sha1Backend::Sequential`] is used instead.
+    #[must_use]
+    pub fn hash_objects<B: AsRef<[u8]>>(self, object_type: &str, bodies: &[B]) -> Vec<[u8; 20]> {
+        match self {
+            Self::Avx2 if self.is_available() => hash_objects_in_lanes(object_type, bodies),
+            _ => bodies
+                .iter()
+                .map(|body| digest_object::<sha1::Sha1>(object_type, body.as_ref()).into())
+                .collect(),
+        }
+    }
+}
+
+/// The SHA-1 state of [`LANES`] messages, or one 32-bit word of each.
+type Words = [u32; LANES];
+
+/// The initial SHA-1 state.
+const INITIAL_STATE: [u32; 5] = [
+    0x6745_2301,
+    0xEFCD_AB89,
+    0x98BA_DCFE,
+    0x1032_5476,
+    0xC3D2_E1F0,
+];
+
+/// Hashes objects with [`Sha1Backend::Avx2`].
+fn hash_objects_in_lanes<B: AsRef<[u8]>>(object_type: &str, bodies: &[B]) -> Vec<[u8; 20]> {
+    let messages = bodies
+        .iter()
+        .map(|body| padded_message(object_type, body.as_ref()))
+        .collect::<Vec<_>>();
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if Sha1Backend::Avx2.is_available() {
+        #[target_feature(enable = "avx2")]
+        #[allow(unsafe_code)]
+        unsafe fn hash_messages_avx2(messages: &[Vec<u8>]) -> Vec<[u8; 20]> {
+            hash_messages(messages)
+        }
+
+        // SAFETY: we just checked that the CPU supports AVX2.
+        #[allow(unsafe_code)]
+        return unsafe { hash_messages_avx2(&messages) };
+    }
+
+    hash_messages(&messages)
+}
+
+/// Hashes padded messages [`LANES`] at a time. Messages are grouped by their
+/// length in blocks, since every lane must be the same length; any that don't
+/// fill a group are hashed with some lanes left empty.
+#[inline(always)]
+fn hash_messages(messages: &[Vec<u8>]) -> Vec<[u8; 20]> {
+    let mut order = (0..messages.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| messages[i].len());
+
+    let mut ids = vec![[0; 20]; messages.len()];
+    for (blocks, same_length) in &order.into_iter().group_by(|&i| messages[i].len() / 64) {
+        for group in &same_length.chunks(LANES) {
+            let group = group.collect::<Vec<_>>();
+
+            let mut state = INITIAL_STATE.map(|word| [word; LANES]);
+            for block in 0..blocks {
+                let mut words = [[0; LANES]; 16];
+                for (lane, &i) in group.iter().enumerate() {
+                    let block = &messages[i][block * 64..(block + 1) * 64];
+                    for (word, bytes) in words.iter_mut().zip(block.chunks_exact(4)) {
+                        word[lane] = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                    }
+                }
+                compress_lanes(&mut state, &words);
+            }
+
+            for (lane, &i) in group.iter().enumerate() {
+                for (word, bytes) in state.iter().zip(ids[i].chunks_exact_mut(4)) {
+                    bytes.copy_from_slice(&word[lane].to_be_bytes());
+                }
+            }
+        }
+    }
+    ids
+}
+
+/// Builds the complete SHA-1 input for a Git object, including its header
+/// and the SHA-1 padding, so that its length is a multiple of 64 bytes.
+fn padded_message(object_type: &str, body: &[u8]) -> Vec<u8> {
+    let header = format!("{} {}\0", object_type, body.len());
+    let length = header.len() + body.len();
+    let mut message = Vec::with_capacity((length + 9 + 63) / 64 * 64);
+    message.extend(header.as_bytes());
+    message.extend(body);
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((length as u64 * 8).to_be_bytes());
+    message
+}
+
+/// The SHA-1 compression function, applied to one block of each of [`LANES`]
+/// messages at once. Every operation is applied to every lane before moving on
+/// to the next, so the compiler can vectorize it.
+#[inline(always)]
+#[allow(clippy::needless_range_loop)]
+fn compress_lanes(state: &mut [Words; 5], block: &[Words; 16]) {
+    let mut schedule = [[0; LANES]; 80];
+    schedule[..16].copy_from_slice(block);
+    for t in 16..80 {
+        for lane in 0..LANES {
+            schedule[t][lane] = (schedule[t - 3][lane]
+                ^ schedule[t - 8][lane]
+                ^ schedule[t - 14][lane]
+                ^ schedule[t - 16][lane])
+                .rotate_left(1);
+        }
+    }
+
+    let mut working = *state;
+    for words in &schedule[..20] {
+        round(&mut working, words, 0x5A82_7999, |b, c, d| {
+            (b & c) | (!b & d)
+        });
+    }
+    for words in &schedule[20..40] {
+        round(&mut working, words, 0x6ED9_EBA1, |b, c, d| b ^ c ^ d);
+    }
+    for words in &schedule[40..60] {
+        round(&mut working, words, 0x8F1B_BCDC, |b, c, d| {
+            (b & c) | (b & d) | (c & d)
+        });
+    }
+    for words in &schedule[60..] {
+        round(&mut working, words, 0xCA62_C1D6, |b, c, d| b ^ c ^ d);
+    }
+
+    for (state, word) in state.iter_mut().zip(working) {
+        for lane in 0..LANES {
+            state[lane] = state[lane].wrapping_add(word[lane]);
+        }
+    }
+}
+
+/// One round of [`compress_lanes`], with the round function `f` and constant
+/// `k`.
+#[inline(always)]
+#[allow(clippy::many_single_char_names, clippy::needless_range_loop)]
+fn round(working: &mut [Words; 5], words: &Words, k: u32, f: impl Fn(u32, u32, u32) -> u32) {
+    let [a, b, c, d, e] = working;
+    for lane in 0..LANES {
+        let temp = a[lane]
+            .rotate_left(5)
+            .wrapping_add(f(b[lane], c[lane], d[lane]))
+            .wrapping_add(e[lane])
+            .wrapping_add(k)
+            .wrapping_add(words[lane]);
+        e[lane] = d[lane];
+        d[lane] = c[lane];
+        c[lane] = b[lane].rotate_left(30);
+        b[lane] = a[lane];
+        a[lane] = temp;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{fs, process::Command},
+    };
+
+    /// Runs `git` in `dir`, returning its trimmed output.
+    fn git(dir: &Path, args: &[&str]) -> Result<String> {
+        let output = Command::new("git").current_dir(dir).args(args).output()?;
+        if !output.status.success() {
+            bail!(
+                "git {:?} failed: {}",
+                args,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(String::from_utf8(output.stdout)?.trim().to_string())
+    }
+
+    #[test]
+    fn hashes_objects_like_git() -> Result<()> {
+        for format in [ObjectFormat::Sha1, ObjectFormat::Sha256] {
+            let dir = tempfile::tempdir()?;
+            git(
+                dir.path(),
+                &["init", "--quiet", "--object-format", format.name()],
+            )?;
+            assert_eq!(
+                ObjectFormat::discover(dir.path())?,
+                Some((dir.path().join(".git"), format))
+            );
+
+            fs::write(dir.path().join("file.txt"), "hello\n")?;
+            let blob = git(dir.path(), &["hash-object", "-w", "file.txt"])?;
+            assert_eq!(blob.len(), format.id_length() * 2);
+            assert_eq!(blob, hex::encode(format.hash_object("blob", b"hello\n")));
+
+            git(dir.path(), &["add", "file.txt"])?;
+            let tree = git(dir.path(), &["write-tree"])?;
+            let commit = git(
+                dir.path(),
+                &[
+                    "-c",
+                    "user.name=Tester",
+                    "-c",
+                    "user.email=tester@example.com",
+                    "commit-tree",
+                    &tree,
+                    "-m",
+                    "Test",
+                ],
+            )?;
+            for (object_type, id) in [("tree", &tree), ("commit", &commit)] {
+                let body = Command::new("git")
+                    .current_dir(dir.path())
+                    .args(["cat-file", object_type, id])
+                    .output()?
+                    .stdout;
+                assert_eq!(*id, hex::encode(format.hash_object(object_type, &body)));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn backends_match_the_sha1_crate() {
+        // with their 7 to 9 byte "blob N\0" headers, these messages cross the
+        // padding boundaries at 55, 56 and 64 bytes, and the same ones in
+        // later blocks, up to dozens of blocks long
+        let bodies = (0..=200)
+            .chain([1_000, 4_096])
+            .map(|length: usize| {
+                (0..length)
+                    .map(|i| (i * 7 + length).to_le_bytes()[0])
+                    .collect()
+            })
+            .collect::<Vec<Vec<u8>>>();
+        let expected = bodies
+            .iter()
+            .map(|body| <[u8; 20]>::from(digest_object::<sha1::Sha1>("blob", body)))
+            .collect::<Vec<_>>();
+
+        for backend in Sha1Backend::ALL {
+            assert_eq!(
+                backend.hash_objects("blob", &bodies),
+                expected,
+                "{:?}",
+                backend
+            );
+        }
+        // the lanes even without AVX2, including partly empty groups
+        for count in [1, LANES - 1, LANES, LANES + 1, bodies.len()] {
+            let messages = bodies[..count]
+                .iter()
+                .map(|body| padded_message("blob", body))
+                .collect::<Vec<_>>();
+            assert_eq!(hash_messages(&messages), expected[..count]);
+        }
+        assert_eq!(hash_objects_in_lanes("blob", &bodies), expected);
+
+        assert!(Sha1Backend::detect().is_available());
+    }
+}
@@ -0,0 +1,143 @@
+//! Running Git hooks around commits.
+
+use {
+    eyre::{bail, Result, WrapErr},
+    git2::Repository,
+    std::{
+        ffi::OsStr,
+        path::{Path, PathBuf},
+        process::{Command, ExitStatus, Stdio},
+    },
+    tracing::{debug, info, instrument, trace, warn},
+};
+
+/// The hooks configured for a [`Repository`], in `core.hooksPath` or else in
+/// the `hooks` directory inside of the Git directory.
+#[derive(Debug, Clone)]
+pub struct Hooks {
+    /// The directory containing the hook scripts.
+    dir: PathBuf,
+    /// The directory hooks are run from: the root of the working tree.
+    workdir: PathBuf,
+    /// The Git directory, containing the index.
+    git_dir: PathBuf,
+}
+
+impl Hooks {
+    /// Finds the hooks for a [`Repository`].
+    ///
+    /// # Errors
+    ///
+    /// If the repository is bare or its configuration can't be read.
+    #[instrument(level = "debug", skip_all)]
+    pub fn new(repo: &Repository) -> Result<Self> {
+        let workdir = match repo.workdir() {
+            Some(workdir) => workdir.to_path_buf(),
+            None => bail!("Can't run hooks in a bare repository."),
+        };
+        let git_dir = repo.path().to_path_buf();
+
+        let dir = match repo.config()?.get_path("core.hooksPath") {
+            Ok(path) if path.is_relative() => workdir.join(path),
+            Ok(path) => path,
+            Err(_) => git_dir.join("hooks"),
+        };
+        debug!("Using hooks from: {:?}", dir);
+
+        Ok(Self {
+            dir,
+            workdir,
+            git_dir,
+        })
+    }
+
+    /// Returns the path to the named hook, if it exists and is executable.
+    #[must_use]
+    pub fn find(&self, name: &str) -> Option<PathBuf> {
+        let path = self.dir.join(name);
+        if is_executable(&path) {
+            Some(path)
+        } else {
+            trace!("No executable {} hook at {:?}", name, path);
+            None
+        }
+    }
+
+    /// Runs the named hook with the given arguments, if it exists.
+    ///
+    /// The hook's output is passed through to our own stdout and stderr.
+    ///
+    /// # Errors
+    ///
+    /// If the hook can't be run or exits with a non-zero status.
+    pub fn run(&self, name: &str, args: &[&OsStr]) -> Result<()> {
+        self.run_with_index(name, args, &self.git_dir.join("index"))
+    }
+
+    /// Like [`Hooks::run`], but with `GIT_INDEX_FILE` pointing the hook at
+    /// another index file, like `git commit -a` does when what it's about to
+    /// commit isn't in the repository's own index.
+    ///
+    /// # Errors
+    ///
+    /// If the hook can't be run or exits with a non-zero status.
+    pub fn run_with_index(&self, name: &str, args: &[&OsStr], index_file: &Path) -> Result<()> {
+        match self.status(name, args, index_file)? {
+            Some(status) if !status.success() => {
+                bail!("The {} hook failed ({}), aborting.", name, status)
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs the named hook like [`Hooks::run`], but only logs a warning if it
+    /// fails, as Git does for `post-commit`: the commit has already been made,
+    /// so it's too late to abort.
+    pub fn run_after_commit(&self, name: &str, args: &[&OsStr]) {
+        match self.status(name, args, &self.git_dir.join("index")) {
+            Ok(Some(status)) if !status.success() => warn!(
+                "The {} hook failed ({}), but the commit was saved anyway.",
+                name, status
+            ),
+            Ok(_) => {},
+            Err(err) => warn!("{:#}", err),
+        }
+    }
+
+    /// Runs the named hook, if it exists, returning its exit status.
+    #[instrument(level = "debug", skip(self, args))]
+    fn status(&self, name: &str, args: &[&OsStr], index_file: &Path) -> Result<Option<ExitStatus>> {
+        let path = match self.find(name) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        info!("Running {} hook.", name);
+        let status = Command::new(&path)
+            .args(args)
+            .current_dir(&self.workdir)
+            .env("GIT_INDEX_FILE", index_file)
+            .env("GIT_EDITOR", ":")
+            .stdin(Stdio::null())
+            .status()
+            .wrap_err_with(|| format!("Failed to run {} hook: {:?}", name, path))?;
+
+        Ok(Some(status))
+    }
+}
+
+/// Whether `path` is a file we're allowed to execute.
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// Whether `path` is a file we're allowed to execute.
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
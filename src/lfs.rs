@@ -0,0 +1,359 @@
+//! Storing files tracked by Git LFS (with `filter=lfs` in `.gitattributes`)
+//! the way its clean filter does, since libgit2 doesn't run filters.
+//!
+//! The file's content is stored in `.git/lfs/objects`, and a small pointer to
+//! it is committed in its place. See
+//! <https://github.com/git-lfs/git-lfs/blob/main/docs/spec.md>.
+
+use {
+    crate::tree::is_executable,
+    eyre::{bail, eyre, Result, WrapErr},
+    git2::{AttrCheckFlags, Delta, Index, IndexEntry, IndexTime, Oid, Repository, Tree},
+    sha2::{Digest, Sha256},
+    std::{
+        fmt::{self, Display},
+        fs::{self, File},
+        io,
+        path::{Path, PathBuf},
+    },
+    tempfile::NamedTempFile,
+    tracing::{debug, info, instrument, trace},
+};
+
+/// The version line at the start of every pointer file.
+const VERSION_LINE: &str = "version https://git-lfs.github.com/spec/v1\n";
+
+/// Pointer files can't be larger than this, per the spec.
+const MAX_POINTER_SIZE: usize = 1024;
+
+/// A Git LFS pointer, identifying a file's content by its SHA-256 hash.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Pointer {
+    /// The lowercase hex SHA-256 hash of the content.
+    pub oid: String,
+    /// The size of the content, in bytes.
+    pub size: u64,
+}
+
+impl Pointer {
+    /// Returns the pointer for some content.
+    #[must_use]
+    pub fn for_content(content: &[u8]) -> Self {
+        Self {
+            oid: hex::encode(Sha256::digest(content)),
+            size: content.len() as u64,
+        }
+    }
+
+    /// Returns the pointer for the content of a file, without reading it all
+    /// into memory.
+    ///
+    /// # Errors
+    ///
+    /// If the file can't be read.
+    pub fn for_file(path: &Path) -> Result<Self> {
+        let mut hasher = Sha256::new();
+        let size = io::copy(&mut File::open(path)?, &mut hasher)?;
+        Ok(Self {
+            oid: hex::encode(hasher.finalize()),
+            size,
+        })
+    }
+
+    /// Parses a pointer file, returning `None` if it isn't one.
+    #[must_use]
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() > MAX_POINTER_SIZE {
+            return None;
+        }
+        let text = std::str::from_utf8(bytes).ok()?;
+        let rest = text.strip_prefix(VERSION_LINE)?;
+
+        let (mut oid, mut size) = (None, None);
+        for line in rest.lines() {
+            if let Some(value) = line.strip_prefix("oid sha256:") {
+                if value.len() == 64
+                    && value
+                        .bytes()
+                        .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+                {
+                    oid = Some(value.to_string());
+                }
+            } else if let Some(value) = line.strip_prefix("size ") {
+                size = value.parse().ok();
+            }
+        }
+
+        Some(Self {
+            oid: oid?,
+            size: size?,
+        })
+    }
+
+    /// Returns the serialized pointer file, with its keys sorted as the spec
+    /// requires.
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+
+    /// Returns the path where the content is stored in the repository's local
+    /// LFS object store.
+    #[must_use]
+    pub fn object_path(&self, repo: &Repository) -> PathBuf {
+        self.object_dir(repo).join(&self.oid)
+    }
+
+    /// Returns the directory containing [`Pointer::object_path`].
+    fn object_dir(&self, repo: &Repository) -> PathBuf {
+        repo.path()
+            .join("lfs")
+            .join("objects")
+            .join(&self.oid[0..2])
+            .join(&self.oid[2..4])
+    }
+}
+
+impl Display for Pointer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}oid sha256:{}\nsize {}\n",
+            VERSION_LINE, self.oid, self.size
+        )
+    }
+}
+
+/// Whether `path` (relative to the root of the working tree) is tracked by
+/// Git LFS.
+#[must_use]
+pub fn is_tracked(repo: &Repository, path: &Path) -> bool {
+    matches!(
+        repo.get_attr(path, "filter", AttrCheckFlags::FILE_THEN_INDEX),
+        Ok(Some("lfs"))
+    )
+}
+
+/// Returns what Git LFS's clean filter would commit for the file at `path`,
+/// along with its pointer: the file's content unchanged if it's already a
+/// pointer (after a checkout with `GIT_LFS_SKIP_SMUDGE=1`, for example), or
+/// a pointer to its content otherwise.
+///
+/// # Errors
+///
+/// If the file can't be read.
+pub fn clean(path: &Path) -> Result<(Pointer, Vec<u8>)> {
+    if let Some(existing) = read_pointer(path)? {
+        return Ok(existing);
+    }
+    let pointer = Pointer::for_file(path)?;
+    let bytes = pointer.to_bytes();
+    Ok((pointer, bytes))
+}
+
+/// Returns the pointer in the file at `path`, and the file's content, if it's
+/// a pointer file.
+fn read_pointer(path: &Path) -> Result<Option<(Pointer, Vec<u8>)>> {
+    if path.metadata()?.len() > MAX_POINTER_SIZE as u64 {
+        return Ok(None);
+    }
+    let content = fs::read(path)?;
+    Ok(Pointer::parse(&content).map(|pointer| (pointer, content)))
+}
+
+/// Adds a pointer to the LFS-tracked file at `path` (relative to the root of
+/// the working tree at `workdir`) to an `index` that belongs to a repository.
+///
+/// The content itself isn't stored until [`store_objects`] is called.
+///
+/// # Errors
+///
+/// If the file can't be read, or the pointer can't be written.
+#[instrument(level = "trace", skip(index, workdir))]
+pub fn stage(index: &mut Index, workdir: &Path, path: &Path) -> Result<Pointer> {
+    let full_path = workdir.join(path);
+    let metadata = full_path.metadata()?;
+    let (pointer, bytes) = clean(&full_path)?;
+    trace!("Adding LFS pointer for {}: {}", path.display(), pointer.oid);
+
+    index.add_frombuffer(
+        &IndexEntry {
+            ctime: IndexTime::new(0, 0),
+            mtime: IndexTime::new(0, 0),
+            dev: 0,
+            ino: 0,
+            mode: if is_executable(&metadata) {
+                0o100_755
+            } else {
+                0o100_644
+            },
+            uid: 0,
+            gid: 0,
+            file_size: 0,
+            id: Oid::zero(),
+            flags: 0,
+            flags_extended: 0,
+            path: path
+                .to_str()
+                .ok_or_else(|| eyre!("LFS path isn't UTF-8: {:?}", path))?
+                .as_bytes()
+                .to_vec(),
+        },
+        &bytes,
+    )?;
+
+    Ok(pointer)
+}
+
+/// Stores the content of every LFS pointer added or changed between `old` and
+/// `new` in the local LFS object store, copying it from the working tree.
+/// Returns the number of objects that were stored.
+///
+/// # Errors
+///
+/// If a file can't be copied, or if it no longer matches its pointer.
+#[instrument(level = "debug", skip_all)]
+pub fn store_objects(repo: &Repository, old: Option<&Tree>, new: &Tree) -> Result<usize> {
+    let workdir = match repo.workdir() {
+        Some(workdir) => workdir,
+        None => return Ok(0),
+    };
+    let diff = repo.diff_tree_to_tree(old, Some(new), None)?;
+
+    let mut stored = 0;
+    for delta in diff.deltas() {
+        if !matches!(delta.status(), Delta::Added | Delta::Modified) {
+            continue;
+        }
+        let file = delta.new_file();
+        let (path, blob) = match (file.path(), repo.find_blob(file.id())) {
+            (Some(path), Ok(blob)) => (path, blob),
+            _ => continue,
+        };
+        let pointer = match Pointer::parse(blob.content()) {
+            Some(pointer) => pointer,
+            None => continue,
+        };
+
+        let object_path = pointer.object_path(repo);
+        if object_path.exists() {
+            trace!("LFS object already stored: {}", pointer.oid);
+            continue;
+        }
+
+        let source = workdir.join(path);
+        if read_pointer(&source)?.is_some() {
+            // the working tree only has the pointer, not the content
+            debug!("LFS object isn't available locally: {}", pointer.oid);
+            continue;
+        }
+        let dir = pointer.object_dir(repo);
+        fs::create_dir_all(&dir)?;
+        let mut temporary = NamedTempFile::new_in(&dir)?;
+        io::copy(&mut File::open(&source)?, &mut temporary)
+            .wrap_err_with(|| format!("Failed to copy {} into LFS storage", path.display()))?;
+
+        if Pointer::for_file(temporary.path())? != pointer {
+            bail!(
+                "{} changed while it was being saved, aborting.",
+                path.display()
+            );
+        }
+        temporary.persist(&object_path)?;
+        debug!("Stored LFS object for {}: {}", path.display(), pointer.oid);
+        stored += 1;
+    }
+
+    if stored > 0 {
+        info!("Stored {} new Git LFS object(s).", stored);
+    }
+    Ok(stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{git2::RepositoryExt, guardrails::Guardrails},
+    };
+
+    /// The SHA-256 hash of `hello`.
+    const HELLO_OID: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn pointers_have_the_spec_format() -> Result<()> {
+        let pointer = Pointer::for_content(b"hello");
+        assert_eq!(pointer.oid, HELLO_OID);
+        assert_eq!(pointer.size, 5);
+        assert_eq!(
+            pointer.to_bytes(),
+            format!(
+                "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 5\n",
+                HELLO_OID
+            )
+            .into_bytes()
+        );
+        assert_eq!(Pointer::parse(&pointer.to_bytes()), Some(pointer.clone()));
+
+        let file = NamedTempFile::new()?;
+        fs::write(file.path(), "hello")?;
+        assert_eq!(Pointer::for_file(file.path())?, pointer);
+
+        Ok(())
+    }
+
+    #[test]
+    fn stores_tracked_files_as_pointers() -> Result<()> {
+        let repo = Repository::temporary()?;
+        let workdir = repo.workdir().unwrap();
+        fs::write(workdir.join(".gitattributes"), "*.bin filter=lfs -text\n")?;
+        fs::write(workdir.join("data.bin"), "hello")?;
+
+        let tree = repo
+            .working_index(&[], &Guardrails::disabled())?
+            .write_tree()?;
+        let tree = repo.find_tree(tree)?;
+        let blob = repo.find_blob(tree.get_name("data.bin").unwrap().id())?;
+        assert_eq!(blob.content(), Pointer::for_content(b"hello").to_bytes());
+
+        assert_eq!(store_objects(&repo, None, &tree)?, 1);
+        let stored = repo.path().join("lfs/objects/2c/f2").join(HELLO_OID);
+        assert_eq!(fs::read(stored)?, b"hello");
+
+        // already stored
+        assert_eq!(store_objects(&repo, None, &tree)?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn stages_existing_pointers_unchanged() -> Result<()> {
+        let repo = Repository::temporary()?;
+        let workdir = repo.workdir().unwrap();
+        fs::write(workdir.join(".gitattributes"), "*.bin filter=lfs -text\n")?;
+        // as checked out with GIT_LFS_SKIP_SMUDGE=1, with an extension key
+        let pointer = format!(
+            "version https://git-lfs.github.com/spec/v1\next-0-foo sha256:{}\noid sha256:{}\nsize 5\n",
+            HELLO_OID, HELLO_OID
+        );
+        fs::write(workdir.join("data.bin"), &pointer)?;
+
+        let tree = repo
+            .working_index(&[], &Guardrails::disabled())?
+            .write_tree()?;
+        let tree = repo.find_tree(tree)?;
+        let blob = repo.find_blob(tree.get_name("data.bin").unwrap().id())?;
+        assert_eq!(blob.content(), pointer.as_bytes());
+        assert_eq!(tree.id(), repo.working_tree_id()?);
+
+        // the content isn't available to store
+        assert_eq!(store_objects(&repo, None, &tree)?, 0);
+        assert!(!repo
+            .path()
+            .join("lfs/objects/2c/f2")
+            .join(HELLO_OID)
+            .exists());
+
+        Ok(())
+    }
+}
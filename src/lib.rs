@@ -63,3 +63,13 @@
 
 pub mod cli;
 pub mod git2;
+pub mod guardrails;
+pub mod hash;
+pub mod hooks;
+pub mod lfs;
+pub mod log;
+pub mod object;
+pub mod search;
+pub mod secrets;
+pub mod signing;
+pub mod tree;
@@ -0,0 +1,475 @@
+//! Rendering recent commits like `git log --graph --name-status
+//! --format=fuller --date=relative`, without running `git`.
+
+use {
+    eyre::Result,
+    git2::{Commit, Delta, DiffFindOptions, Oid, Repository, Signature, Sort},
+    std::{
+        collections::HashMap,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    termcolor::{Color, ColorSpec, WriteColor},
+};
+
+/// Writes the `count` most recent commits reachable from `start` to `out`,
+/// with the files each one changed, like `git log --graph --name-status
+/// --format=fuller --date=relative --decorate -n <count>`.
+///
+/// The graph is simplified: it only draws a diagonal where a merge forks a
+/// new column immediately to its right, or where a column joins the one
+/// immediately to its left. Merges are shown without a list of changed files,
+/// as `git log` does by default.
+///
+/// # Errors
+///
+/// If any commit or reference can't be read, or writing to `out` fails.
+pub fn write_commits(
+    repo: &Repository,
+    start: Oid,
+    count: usize,
+    out: &mut dyn WriteColor,
+) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| i64::try_from(now.as_secs()).unwrap_or(i64::MAX));
+    write_commits_at(repo, start, count, now, out)
+}
+
+/// Like [`write_commits`], with dates relative to `now` instead of the
+/// current time.
+fn write_commits_at(
+    repo: &Repository,
+    start: Oid,
+    count: usize,
+    now: i64,
+    out: &mut dyn WriteColor,
+) -> Result<()> {
+    let decorations = decorations(repo)?;
+
+    let mut walk = repo.revwalk()?;
+    walk.push(start)?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+
+    // the commit expected next in each column of the graph
+    let mut columns: Vec<Oid> = vec![];
+    // whether the last commit was followed by a blank or diagonal line
+    let mut separated = true;
+    for id in walk.take(count) {
+        let commit = repo.find_commit(id?)?;
+
+        if !separated {
+            write_line(out, columns.len(), "")?;
+        }
+        separated = false;
+
+        let column = if let Some(column) = columns.iter().position(|&id| id == commit.id()) {
+            column
+        } else {
+            columns.push(commit.id());
+            columns.len() - 1
+        };
+        for other in 0..columns.len() {
+            write!(out, "{} ", if other == column { '*' } else { '|' })?;
+        }
+        out.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))?;
+        write!(out, "commit {}", commit.id())?;
+        out.reset()?;
+        if let Some(names) = decorations.get(&commit.id()) {
+            write_decorations(out, names)?;
+        }
+        writeln!(out)?;
+
+        // replace the commit with its parents, joining any column that was
+        // already expecting its first parent
+        let width = columns.len();
+        let parents = commit.parent_ids().collect::<Vec<_>>();
+        let mut joined = None;
+        match parents.first() {
+            Some(&first) => match columns.iter().position(|&id| id == first) {
+                Some(existing) if existing < column => {
+                    columns.remove(column);
+                    joined = Some(existing);
+                },
+                Some(existing) => {
+                    columns[column] = first;
+                    columns.remove(existing);
+                },
+                None => columns[column] = first,
+            },
+            None => {
+                columns.remove(column);
+            },
+        }
+        for &parent in parents.iter().skip(1) {
+            if !columns.contains(&parent) {
+                columns.push(parent);
+            }
+        }
+
+        let body_columns = if joined.is_some() {
+            width
+        } else {
+            if parents.len() > 1 {
+                let merge = parents.iter().map(|&id| short(id)).collect::<Vec<_>>();
+                write_line(out, width, &format!("Merge: {}", merge.join(" ")))?;
+                if columns.len() == width + 1 && column + 1 == width {
+                    writeln!(out, "{}|\\", "| ".repeat(column))?;
+                }
+            }
+            columns.len()
+        };
+
+        let author = commit.author();
+        let committer = commit.committer();
+        for (label, text) in [
+            ("Author:    ", identity(&author)),
+            ("AuthorDate:", relative_date(author.when().seconds(), now)),
+            ("Commit:    ", identity(&committer)),
+            (
+                "CommitDate:",
+                relative_date(committer.when().seconds(), now),
+            ),
+        ] {
+            write_line(out, body_columns, &format!("{} {}", label, text))?;
+        }
+        write_line(out, body_columns, "")?;
+        for line in String::from_utf8_lossy(commit.message_bytes())
+            .trim_end()
+            .lines()
+        {
+            write_line(out, body_columns, &format!("    {}", line))?;
+        }
+
+        if parents.len() < 2 {
+            let changes = name_status(repo, &commit)?;
+            if !changes.is_empty() {
+                write_line(out, body_columns, "")?;
+                for change in changes {
+                    write_line(out, body_columns, &change)?;
+                }
+            }
+        }
+
+        if let Some(joined) = joined {
+            if joined + 1 == column && column + 1 == width {
+                writeln!(out, "{}|/", "| ".repeat(joined))?;
+                separated = true;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a line of text after the graph's `columns`.
+fn write_line(out: &mut dyn WriteColor, columns: usize, text: &str) -> Result<()> {
+    let graph = if columns == 0 {
+        "  ".to_string()
+    } else {
+        "| ".repeat(columns)
+    };
+    let line = format!("{}{}", graph, text);
+    writeln!(out, "{}", line.trim_end())?;
+    Ok(())
+}
+
+/// Writes the names of the references pointing to a commit, in parentheses.
+fn write_decorations(out: &mut dyn WriteColor, names: &[(String, Color)]) -> Result<()> {
+    let punctuation = ColorSpec::new().set_fg(Some(Color::Yellow)).clone();
+    out.set_color(&punctuation)?;
+    write!(out, " (")?;
+    for (i, (name, color)) in names.iter().enumerate() {
+        if i > 0 {
+            out.set_color(&punctuation)?;
+            write!(out, ", ")?;
+        }
+        out.set_color(ColorSpec::new().set_fg(Some(*color)).set_bold(true))?;
+        write!(out, "{}", name)?;
+    }
+    out.set_color(&punctuation)?;
+    write!(out, ")")?;
+    out.reset()?;
+    Ok(())
+}
+
+/// Returns the names to show next to each commit, as `git log --decorate`
+/// would, and the color for each.
+fn decorations(repo: &Repository) -> Result<HashMap<Oid, Vec<(String, Color)>>> {
+    let mut decorations = HashMap::<Oid, Vec<(String, Color)>>::new();
+
+    let head = repo.head().ok();
+    let head_branch = head
+        .as_ref()
+        .filter(|head| head.is_branch())
+        .and_then(|head| head.shorthand().map(str::to_string));
+    if let Some(id) = head
+        .and_then(|head| head.peel_to_commit().ok())
+        .map(|c| c.id())
+    {
+        let name = match head_branch {
+            Some(ref branch) => format!("HEAD -> {}", branch),
+            None => "HEAD".to_string(),
+        };
+        decorations.entry(id).or_default().push((name, Color::Cyan));
+    }
+
+    for reference in repo.references()? {
+        let reference = reference?;
+        let (name, shorthand) = match (reference.name(), reference.shorthand()) {
+            (Some(name), Some(shorthand)) => (name, shorthand),
+            _ => continue,
+        };
+        let (name, color) = if name.starts_with("refs/heads/") {
+            if head_branch.as_deref() == Some(shorthand) {
+                continue;
+            }
+            (shorthand.to_string(), Color::Green)
+        } else if name.starts_with("refs/remotes/") {
+            (shorthand.to_string(), Color::Red)
+        } else if name.starts_with("refs/tags/") {
+            (format!("tag: {}", shorthand), Color::Yellow)
+        } else {
+            continue;
+        };
+        if let Ok(commit) = reference.peel_to_commit() {
+            decorations
+                .entry(commit.id())
+                .or_default()
+                .push((name, color));
+        }
+    }
+
+    Ok(decorations)
+}
+
+/// Returns the files changed by a commit, compared to its first parent (if
+/// any), like `git log --name-status`.
+fn name_status(repo: &Repository, commit: &Commit) -> Result<Vec<String>> {
+    let old_tree = commit
+        .parents()
+        .next()
+        .map(|parent| parent.tree())
+        .transpose()?;
+    let mut diff = repo.diff_tree_to_tree(old_tree.as_ref(), Some(&commit.tree()?), None)?;
+    diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+
+    Ok(diff
+        .deltas()
+        .filter_map(|delta| {
+            let status = match delta.status() {
+                Delta::Added => 'A',
+                Delta::Deleted => 'D',
+                Delta::Modified => 'M',
+                Delta::Renamed => 'R',
+                Delta::Copied => 'C',
+                Delta::Typechange => 'T',
+                _ => return None,
+            };
+            let old_path = delta.old_file().path()?.display();
+            let new_path = delta.new_file().path()?.display();
+            Some(match delta.status() {
+                Delta::Renamed | Delta::Copied => format!("{}\t{}\t{}", status, old_path, new_path),
+                Delta::Deleted => format!("{}\t{}", status, old_path),
+                _ => format!("{}\t{}", status, new_path),
+            })
+        })
+        .collect())
+}
+
+/// Formats a signature's name and email address.
+fn identity(signature: &Signature) -> String {
+    format!(
+        "{} <{}>",
+        String::from_utf8_lossy(signature.name_bytes()),
+        String::from_utf8_lossy(signature.email_bytes())
+    )
+}
+
+/// Abbreviates a commit ID to 7 hex digits.
+fn short(id: Oid) -> String {
+    id.to_string()[..7].to_string()
+}
+
+/// Describes a timestamp relative to `now`, like `10 minutes ago`, the same
+/// way as Git's `show_date_relative`: each unit is rounded from the last, and
+/// anything after `now` is just `in the future`.
+fn relative_date(seconds: i64, now: i64) -> String {
+    if seconds > now {
+        return "in the future".to_string();
+    }
+
+    let seconds = now - seconds;
+    if seconds < 90 {
+        return format!("{} ago", plural(seconds, "second"));
+    }
+    let minutes = (seconds + 30) / 60;
+    if minutes < 90 {
+        return format!("{} ago", plural(minutes, "minute"));
+    }
+    let hours = (minutes + 30) / 60;
+    if hours < 36 {
+        return format!("{} ago", plural(hours, "hour"));
+    }
+    let days = (hours + 12) / 24;
+    if days < 14 {
+        return format!("{} ago", plural(days, "day"));
+    }
+    if days < 70 {
+        return format!("{} ago", plural((days + 3) / 7, "week"));
+    }
+    if days < 365 {
+        return format!("{} ago", plural((days + 15) / 30, "month"));
+    }
+    if days < 1825 {
+        let months = (days * 12 * 2 + 365) / (365 * 2);
+        let years = plural(months / 12, "year");
+        return match months % 12 {
+            0 => format!("{} ago", years),
+            months => format!("{}, {} ago", years, plural(months, "month")),
+        };
+    }
+    format!("{} ago", plural((days + 183) / 365, "year"))
+}
+
+/// Formats a number of units, like `1 second` or `2 seconds`.
+fn plural(count: i64, unit: &str) -> String {
+    if count == 1 {
+        format!("{} {}", count, unit)
+    } else {
+        format!("{} {}s", count, unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::git2::RepositoryExt,
+        git2::Time,
+        std::{fs, path::Path},
+        termcolor::NoColor,
+    };
+
+    #[test]
+    fn relative_dates_match_git() {
+        const NOW: i64 = 1_700_000_000;
+        const MINUTE: i64 = 60;
+        const HOUR: i64 = 60 * MINUTE;
+        const DAY: i64 = 24 * HOUR;
+        let ago = |seconds| relative_date(NOW - seconds, NOW);
+
+        assert_eq!(relative_date(NOW + 1, NOW), "in the future");
+        assert_eq!(relative_date(NOW + 10 * DAY, NOW), "in the future");
+        assert_eq!(ago(0), "0 seconds ago");
+        assert_eq!(ago(1), "1 second ago");
+        assert_eq!(ago(89), "89 seconds ago");
+        assert_eq!(ago(90), "2 minutes ago");
+        assert_eq!(ago(89 * MINUTE + 29), "89 minutes ago");
+        assert_eq!(ago(89 * MINUTE + 30), "2 hours ago");
+        assert_eq!(ago(35 * HOUR + 29 * MINUTE + 29), "35 hours ago");
+        assert_eq!(ago(35 * HOUR + 29 * MINUTE + 30), "2 days ago");
+        assert_eq!(ago(13 * DAY + 11 * HOUR), "13 days ago");
+        assert_eq!(ago(14 * DAY), "2 weeks ago");
+        assert_eq!(ago(69 * DAY), "10 weeks ago");
+        assert_eq!(ago(70 * DAY), "2 months ago");
+        assert_eq!(ago(75 * DAY), "3 months ago");
+        assert_eq!(ago(364 * DAY), "12 months ago");
+        assert_eq!(ago(365 * DAY), "1 year ago");
+        assert_eq!(ago(380 * DAY), "1 year ago");
+        assert_eq!(ago(381 * DAY), "1 year, 1 month ago");
+        assert_eq!(ago(800 * DAY), "2 years, 2 months ago");
+        assert_eq!(ago(1824 * DAY), "5 years ago");
+        assert_eq!(ago(1825 * DAY), "5 years ago");
+        assert_eq!(ago(2006 * DAY), "5 years ago");
+        assert_eq!(ago(2007 * DAY), "6 years ago");
+    }
+
+    #[test]
+    fn writes_commits_like_git() -> Result<()> {
+        const NOW: i64 = 1_700_000_000;
+        let repo = Repository::temporary()?;
+        let workdir = repo.workdir().unwrap();
+        let commit = |files: &[(&str, Option<&str>)], seconds: i64| -> Result<Oid> {
+            let mut index = repo.index()?;
+            for (path, content) in files {
+                if let Some(content) = content {
+                    fs::write(workdir.join(path), content)?;
+                    index.add_path(Path::new(path))?;
+                } else {
+                    fs::remove_file(workdir.join(path))?;
+                    index.remove_path(Path::new(path))?;
+                }
+            }
+            index.write()?;
+            let tree = repo.find_tree(index.write_tree()?)?;
+            let author = Signature::new("Author", "author@example.com", &Time::new(seconds, 0))?;
+            let committer = Signature::new(
+                "Committer",
+                "committer@example.com",
+                &Time::new(seconds + 60, 0),
+            )?;
+            let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+            Ok(repo.commit(
+                Some("HEAD"),
+                &author,
+                &committer,
+                "Message\n\nWith a body.\n",
+                &tree,
+                &parent.iter().collect::<Vec<_>>(),
+            )?)
+        };
+        let first = commit(
+            &[("kept.txt", Some("kept")), ("deleted.txt", Some("deleted"))],
+            NOW - 3 * 3600,
+        )?;
+        repo.tag_lightweight("v1", &repo.find_object(first, None)?, false)?;
+        let second = commit(
+            &[
+                ("added.txt", Some("added")),
+                ("kept.txt", Some("modified")),
+                ("deleted.txt", None),
+            ],
+            NOW - 600,
+        )?;
+        let branch = repo.head()?.shorthand().unwrap().to_string();
+
+        let mut out = NoColor::new(vec![]);
+        write_commits_at(&repo, second, 10, NOW, &mut out)?;
+        let expected = format!(
+            "\
+* commit {second} (HEAD -> {branch})
+| Author:     Author <author@example.com>
+| AuthorDate: 10 minutes ago
+| Commit:     Committer <committer@example.com>
+| CommitDate: 9 minutes ago
+|
+|     Message
+|
+|     With a body.
+|
+| A\tadded.txt
+| D\tdeleted.txt
+| M\tkept.txt
+|
+* commit {first} (tag: v1)
+  Author:     Author <author@example.com>
+  AuthorDate: 3 hours ago
+  Commit:     Committer <committer@example.com>
+  CommitDate: 3 hours ago
+
+      Message
+
+      With a body.
+
+  A\tdeleted.txt
+  A\tkept.txt
+",
+            first = first,
+            second = second,
+            branch = branch,
+        );
+        assert_eq!(String::from_utf8(out.into_inner())?, expected);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,656 @@
+//! Parsing and serializing raw Git objects, byte-for-byte.
+
+use {
+    crate::git2::{CommitExt, OidExt, TagExt},
+    eyre::{bail, eyre, Result},
+    git2::{Commit, ObjectType, Oid, Repository, Signature, Tag},
+    std::str,
+};
+
+/// A Git commit object, parsed into its parts such that serializing it with
+/// [`RawCommit::to_bytes`] reproduces the original bytes exactly.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RawCommit {
+    /// The ID of the commit's tree.
+    pub tree: Oid,
+    /// The IDs of the commit's parents, in order.
+    pub parents: Vec<Oid>,
+    /// The `author` header.
+    pub author: RawSignature,
+    /// The `committer` header.
+    pub committer: RawSignature,
+    /// Every header after `committer`, in order, such as `encoding`,
+    /// `mergetag`, and `gpgsig`.
+    pub headers: Vec<RawHeader>,
+    /// Everything after the blank line that ends the headers.
+    pub message: Vec<u8>,
+}
+
+/// A Git annotated tag object, parsed into its parts such that serializing it
+/// with [`RawTag::to_bytes`] reproduces the original bytes exactly.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RawTag {
+    /// The ID of the tagged object.
+    pub object: Oid,
+    /// The `type` header: the type of the tagged object, like `commit`.
+    pub object_type: Vec<u8>,
+    /// The `tag` header: the tag's name, like `v1.2.3`.
+    pub name: Vec<u8>,
+    /// The `tagger` header, which very old tags don't have.
+    pub tagger: Option<RawSignature>,
+    /// Every header after `tagger`, in order, such as the `nonce` added by
+    /// [`RawTag::set_nonce`].
+    pub headers: Vec<RawHeader>,
+    /// Everything after the blank line that ends the headers, including any
+    /// signature.
+    pub message: Vec<u8>,
+}
+
+/// An author or committer in a [`RawCommit`], like
+/// `Jeremy <jeremy@example.com> 1643000000 -0500`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RawSignature {
+    /// Everything before the timestamp: the name and the email address in
+    /// angle brackets, exactly as written.
+    pub identity: Vec<u8>,
+    /// The timestamp, in seconds since the Unix epoch.
+    pub seconds: i64,
+    /// The time zone offset, like `-0500`, exactly as written.
+    pub offset: Vec<u8>,
+}
+
+/// A header in a [`RawCommit`] or [`RawTag`] other than `tree`, `parent`, `author`, or
+/// `committer`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RawHeader {
+    /// The header's name, like `gpgsig`.
+    pub name: Vec<u8>,
+    /// The header's value. Values that span multiple lines are stored
+    /// without the space that indents each continuation line.
+    pub value: Vec<u8>,
+}
+
+impl RawCommit {
+    /// Builds a commit in memory, the same way [`Repository::commit`] would,
+    /// without writing anything to the object database.
+    ///
+    /// [`Repository::commit`]: git2::Repository::commit
+    #[must_use]
+    pub fn new(
+        tree: Oid,
+        parents: &[Oid],
+        author: &Signature,
+        committer: &Signature,
+        message: &str,
+    ) -> Self {
+        Self {
+            tree,
+            parents: parents.to_vec(),
+            author: RawSignature::from_signature(author),
+            committer: RawSignature::from_signature(committer),
+            headers: vec![],
+            message: message.as_bytes().to_vec(),
+        }
+    }
+
+    /// Parses the body of a Git commit object.
+    ///
+    /// # Errors
+    ///
+    /// If the commit is malformed: missing a required header or having
+    /// headers out of order, or if it wouldn't serialize back into exactly
+    /// the same bytes, such as with a non-canonical timestamp or line ending.
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let (header_bytes, message) = match find(bytes, b"\n\n") {
+            Some(i) => (&bytes[..=i], &bytes[i + 2..]),
+            None => bail!("commit has no blank line after its headers"),
+        };
+
+        let mut headers = split_headers(header_bytes)?.into_iter().peekable();
+
+        let tree = match headers.next() {
+            Some(header) if header.name == b"tree" => parse_oid(&header.value)?,
+            _ => bail!("commit doesn't start with a tree header"),
+        };
+
+        let mut parents = vec![];
+        while let Some(header) = headers.next_if(|header| header.name == b"parent") {
+            parents.push(parse_oid(&header.value)?);
+        }
+
+        let author = match headers.next() {
+            Some(header) if header.name == b"author" => RawSignature::parse(&header.value)?,
+            _ => bail!("commit is missing its author header"),
+        };
+        let committer = match headers.next() {
+            Some(header) if header.name == b"committer" => RawSignature::parse(&header.value)?,
+            _ => bail!("commit is missing its committer header"),
+        };
+
+        let commit = Self {
+            tree,
+            parents,
+            author,
+            committer,
+            headers: headers.collect(),
+            message: message.to_vec(),
+        };
+
+        if commit.to_bytes() != bytes {
+            bail!("commit wouldn't serialize back into the same bytes");
+        }
+
+        Ok(commit)
+    }
+
+    /// Parses an existing [`Commit`].
+    ///
+    /// # Errors
+    ///
+    /// See [`RawCommit::parse`].
+    pub fn from_commit(commit: &Commit) -> Result<Self> {
+        Self::parse(&commit.to_bytes())
+    }
+
+    /// Serializes this into the body of a Git commit object.
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(256 + self.message.len());
+
+        bytes.extend(b"tree ");
+        bytes.extend(self.tree.to_string().as_bytes());
+        bytes.push(b'\n');
+
+        for parent in &self.parents {
+            bytes.extend(b"parent ");
+            bytes.extend(parent.to_string().as_bytes());
+            bytes.push(b'\n');
+        }
+
+        bytes.extend(b"author ");
+        self.author.write_to(&mut bytes);
+        bytes.push(b'\n');
+
+        bytes.extend(b"committer ");
+        self.committer.write_to(&mut bytes);
+        bytes.push(b'\n');
+
+        for header in &self.headers {
+            header.write_to(&mut bytes);
+        }
+
+        bytes.push(b'\n');
+        bytes.extend(&self.message);
+        bytes
+    }
+
+    /// Returns the ID this commit would have.
+    #[must_use]
+    pub fn id(&self) -> Oid {
+        Oid::for_object("commit", &self.to_bytes())
+    }
+
+    /// Returns the value of the first header with the given `name`, if any.
+    #[must_use]
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|header| header.name == name.as_bytes())
+            .map(|header| header.value.as_slice())
+    }
+
+    /// Returns the `encoding` header, if the message isn't UTF-8.
+    #[must_use]
+    pub fn encoding(&self) -> Option<&[u8]> {
+        self.header("encoding")
+    }
+
+    /// Returns the `gpgsig` header, if the commit is signed.
+    #[must_use]
+    pub fn gpgsig(&self) -> Option<&[u8]> {
+        self.header("gpgsig")
+    }
+
+    /// Returns the `mergetag` headers, containing the signed tags that were
+    /// merged by this commit.
+    pub fn mergetags(&self) -> impl Iterator<Item = &[u8]> {
+        self.headers
+            .iter()
+            .filter(|header| header.name == b"mergetag")
+            .map(|header| header.value.as_slice())
+    }
+
+    /// Removes every header with the given `name`, returning whether there
+    /// were any.
+    pub fn remove_header(&mut self, name: &str) -> bool {
+        let before = self.headers.len();
+        self.headers.retain(|header| header.name != name.as_bytes());
+        self.headers.len() != before
+    }
+}
+
+impl RawTag {
+    /// Builds an annotated tag in memory, the same way [`Repository::tag`]
+    /// would, without writing anything to the object database.
+    ///
+    /// [`Repository::tag`]: git2::Repository::tag
+    #[must_use]
+    pub fn new(
+        object: Oid,
+        object_type: ObjectType,
+        name: &str,
+        tagger: &Signature,
+        message: &str,
+    ) -> Self {
+        Self {
+            object,
+            object_type: object_type.str().as_bytes().to_vec(),
+            name: name.as_bytes().to_vec(),
+            tagger: Some(RawSignature::from_signature(tagger)),
+            headers: vec![],
+            message: message.as_bytes().to_vec(),
+        }
+    }
+
+    /// Parses the body of a Git tag object.
+    ///
+    /// # Errors
+    ///
+    /// If the tag is malformed: missing a required header or having
+    /// headers out of order, or if it wouldn't serialize back into exactly
+    /// the same bytes, such as with a non-canonical timestamp or line ending.
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let (header_bytes, message) = match find(bytes, b"\n\n") {
+            Some(i) => (&bytes[..=i], &bytes[i + 2..]),
+            None => bail!("tag has no blank line after its headers"),
+        };
+
+        let mut headers = split_headers(header_bytes)?.into_iter().peekable();
+
+        let object = match headers.next() {
+            Some(header) if header.name == b"object" => parse_oid(&header.value)?,
+            _ => bail!("tag doesn't start with an object header"),
+        };
+        let object_type = match headers.next() {
+            Some(header) if header.name == b"type" => header.value,
+            _ => bail!("tag is missing its type header"),
+        };
+        let name = match headers.next() {
+            Some(header) if header.name == b"tag" => header.value,
+            _ => bail!("tag is missing its tag header"),
+        };
+        let tagger = headers
+            .next_if(|header| header.name == b"tagger")
+            .map(|header| RawSignature::parse(&header.value))
+            .transpose()?;
+
+        let tag = Self {
+            object,
+            object_type,
+            name,
+            tagger,
+            headers: headers.collect(),
+            message: message.to_vec(),
+        };
+
+        if tag.to_bytes() != bytes {
+            bail!("tag wouldn't serialize back into the same bytes");
+        }
+
+        Ok(tag)
+    }
+
+    /// Parses an existing [`Tag`] from `repo`.
+    ///
+    /// # Errors
+    ///
+    /// If the tag can't be read from `repo`, or see [`RawTag::parse`].
+    pub fn from_tag(tag: &Tag, repo: &Repository) -> Result<Self> {
+        Self::parse(&tag.to_bytes(repo)?)
+    }
+
+    /// Serializes this into the body of a Git tag object.
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(256 + self.message.len());
+
+        bytes.extend(b"object ");
+        bytes.extend(self.object.to_string().as_bytes());
+        bytes.push(b'\n');
+
+        bytes.extend(b"type ");
+        bytes.extend(&self.object_type);
+        bytes.push(b'\n');
+
+        bytes.extend(b"tag ");
+        bytes.extend(&self.name);
+        bytes.push(b'\n');
+
+        if let Some(ref tagger) = self.tagger {
+            bytes.extend(b"tagger ");
+            tagger.write_to(&mut bytes);
+            bytes.push(b'\n');
+        }
+
+        for header in &self.headers {
+            header.write_to(&mut bytes);
+        }
+
+        bytes.push(b'\n');
+        bytes.extend(&self.message);
+        bytes
+    }
+
+    /// Returns the ID this tag would have.
+    #[must_use]
+    pub fn id(&self) -> Oid {
+        Oid::for_object("tag", &self.to_bytes())
+    }
+
+    /// Whether the message ends with a PGP or SSH signature, which
+    /// would no longer be valid if anything else in the tag changed.
+    #[must_use]
+    pub fn is_signed(&self) -> bool {
+        find(&self.message, b"-----BEGIN PGP SIGNATURE-----").is_some()
+            || find(&self.message, b"-----BEGIN SSH SIGNATURE-----").is_some()
+    }
+
+    /// Sets the `nonce` header, which has no meaning to Git, but changes the
+    /// tag's ID without changing anything else about it. A `nonce` of `0`
+    /// removes the header instead.
+    pub fn set_nonce(&mut self, nonce: u64) {
+        self.headers.retain(|header| header.name != b"nonce");
+        if nonce != 0 {
+            self.headers.push(RawHeader {
+                name: b"nonce".to_vec(),
+                value: nonce.to_string().into_bytes(),
+            });
+        }
+    }
+}
+
+impl RawSignature {
+    /// Converts a [`Signature`], formatting it the way libgit2 does.
+    #[must_use]
+    pub fn from_signature(signature: &Signature) -> Self {
+        let mut identity = signature.name_bytes().to_vec();
+        identity.extend(b" <");
+        identity.extend(signature.email_bytes());
+        identity.push(b'>');
+
+        let when = signature.when();
+        let minutes = when.offset_minutes().abs();
+        let offset = format!("{}{:02}{:02}", when.sign(), minutes / 60, minutes % 60);
+
+        Self {
+            identity,
+            seconds: when.seconds(),
+            offset: offset.into_bytes(),
+        }
+    }
+
+    /// Parses the value of an `author` or `committer` header.
+    ///
+    /// # Errors
+    ///
+    /// If there's no email address, timestamp, or time zone offset, or if
+    /// the timestamp isn't written canonically.
+    pub fn parse(value: &[u8]) -> Result<Self> {
+        let email_end = value
+            .iter()
+            .rposition(|&b| b == b'>')
+            .ok_or_else(|| eyre!("signature has no email address"))?;
+        let identity = &value[..=email_end];
+
+        let rest = value[email_end + 1..]
+            .strip_prefix(b" ")
+            .ok_or_else(|| eyre!("signature has no timestamp"))?;
+        let space = rest
+            .iter()
+            .position(|&b| b == b' ')
+            .ok_or_else(|| eyre!("signature has no time zone offset"))?;
+        let (seconds, offset) = (&rest[..space], &rest[space + 1..]);
+
+        let seconds_str = str::from_utf8(seconds)?;
+        let parsed: i64 = seconds_str.parse()?;
+        if parsed.to_string() != seconds_str {
+            bail!("signature timestamp isn't canonical: {:?}", seconds_str);
+        }
+
+        Ok(Self {
+            identity: identity.to_vec(),
+            seconds: parsed,
+            offset: offset.to_vec(),
+        })
+    }
+
+    /// Returns the name, without the email address.
+    #[must_use]
+    pub fn name(&self) -> &[u8] {
+        let end = self
+            .identity
+            .iter()
+            .position(|&b| b == b'<')
+            .unwrap_or(self.identity.len());
+        let name = &self.identity[..end];
+        name.strip_suffix(b" ").unwrap_or(name)
+    }
+
+    /// Returns the email address, without the angle brackets.
+    #[must_use]
+    pub fn email(&self) -> &[u8] {
+        let start = self
+            .identity
+            .iter()
+            .position(|&b| b == b'<')
+            .map_or(0, |i| i + 1);
+        let end = self.identity.len().saturating_sub(1).max(start);
+        &self.identity[start..end]
+    }
+
+    /// Appends the serialized header value to `bytes`.
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        bytes.extend(&self.identity);
+        bytes.push(b' ');
+        bytes.extend(self.seconds.to_string().as_bytes());
+        bytes.push(b' ');
+        bytes.extend(&self.offset);
+    }
+}
+
+impl RawHeader {
+    /// Appends the serialized header line(s) to `bytes`.
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        bytes.extend(&self.name);
+        bytes.push(b' ');
+        for (i, line) in self.value.split(|&b| b == b'\n').enumerate() {
+            if i > 0 {
+                bytes.extend(b"\n ");
+            }
+            bytes.extend(line);
+        }
+        bytes.push(b'\n');
+    }
+}
+
+/// Splits the header block of an object (including its final newline) into
+/// headers, joining continuation lines.
+fn split_headers(bytes: &[u8]) -> Result<Vec<RawHeader>> {
+    let mut headers: Vec<RawHeader> = vec![];
+    for line in bytes
+        .strip_suffix(b"\n")
+        .unwrap_or(bytes)
+        .split(|&b| b == b'\n')
+    {
+        if let Some(continuation) = line.strip_prefix(b" ") {
+            let header = headers
+                .last_mut()
+                .ok_or_else(|| eyre!("object starts with a continuation line"))?;
+            header.value.push(b'\n');
+            header.value.extend(continuation);
+        } else {
+            let space = line
+                .iter()
+                .position(|&b| b == b' ')
+                .ok_or_else(|| eyre!("header has no value: {:?}", String::from_utf8_lossy(line)))?;
+            headers.push(RawHeader {
+                name: line[..space].to_vec(),
+                value: line[space + 1..].to_vec(),
+            });
+        }
+    }
+    Ok(headers)
+}
+
+/// Parses a hex object ID.
+fn parse_oid(hex: &[u8]) -> Result<Oid> {
+    Ok(Oid::from_str(str::from_utf8(hex)?)?)
+}
+
+/// Returns the index of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::git2::RepositoryExt};
+
+    /// A tree ID that doesn't need to exist, since nothing reads the tree.
+    const TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+    const PARENTS: [&str; 2] = [
+        "1111111111111111111111111111111111111111",
+        "2222222222222222222222222222222222222222",
+    ];
+
+    /// Writes `body` as a commit object and checks that parsing the commit
+    /// reproduces both [`CommitExt::to_bytes`] and its ID.
+    fn assert_round_trips(repo: &Repository, body: &str) -> Result<RawCommit> {
+        let id = repo.odb()?.write(ObjectType::Commit, body.as_bytes())?;
+        let commit = repo.find_commit(id)?;
+        assert_eq!(commit.to_bytes(), body.as_bytes());
+
+        let raw = RawCommit::from_commit(&commit)?;
+        assert_eq!(raw.to_bytes(), commit.to_bytes());
+        assert_eq!(raw.id(), id);
+        Ok(raw)
+    }
+
+    #[test]
+    fn round_trips_signed_commits() -> Result<()> {
+        let repo = Repository::temporary()?;
+        let signature = "-----BEGIN PGP SIGNATURE-----\n\niQEzBAABCAAdFiEE\n=abcd\n-----END PGP \
+                         SIGNATURE-----";
+        let signed = repo.commit_signed(
+            &format!(
+                "tree {}\nauthor A U Thor <author@example.com> 1234567890 +0100\ncommitter C O \
+                 Mitter <committer@example.com> 1234567891 -0530\n\nSigned\n",
+                TREE
+            ),
+            signature,
+            None,
+        )?;
+        let commit = repo.find_commit(signed)?;
+        let raw = RawCommit::from_commit(&commit)?;
+        assert_eq!(raw.to_bytes(), commit.to_bytes());
+        assert_eq!(raw.id(), signed);
+        assert_eq!(raw.gpgsig(), Some(signature.as_bytes()));
+        assert_eq!(raw.author.seconds, 1_234_567_890);
+        assert_eq!(raw.committer.offset, b"-0530");
+
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_merge_commits() -> Result<()> {
+        let repo = Repository::temporary()?;
+        let raw = assert_round_trips(
+            &repo,
+            &format!(
+                "tree {}\nparent {}\nparent {}\nauthor A <a@example.com> 1 +0000\ncommitter C \
+                 <c@example.com> 2 +0000\n\nMerge\n",
+                TREE, PARENTS[0], PARENTS[1]
+            ),
+        )?;
+        assert_eq!(
+            raw.parents,
+            PARENTS
+                .iter()
+                .map(|parent| Oid::from_str(parent))
+                .collect::<Result<Vec<_>, _>>()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_extra_headers() -> Result<()> {
+        let repo = Repository::temporary()?;
+        let raw = assert_round_trips(
+            &repo,
+            &format!(
+                "tree {}\nparent {}\nparent {}\nauthor A <a@example.com> 1 +0000\ncommitter C \
+                 <c@example.com> 2 +0000\nencoding ISO-8859-1\nmergetag object {}\n type \
+                 commit\n tag v1.0\n tagger T <t@example.com> 3 +0000\n \n Release\nx-custom \
+                 value with  spaces \n\n\nMessage\n\nwith no trailing newline",
+                TREE, PARENTS[0], PARENTS[1], PARENTS[1]
+            ),
+        )?;
+        assert_eq!(raw.encoding(), Some(&b"ISO-8859-1"[..]));
+        assert_eq!(
+            raw.mergetags().collect::<Vec<_>>(),
+            [format!(
+                "object {}\ntype commit\ntag v1.0\ntagger T <t@example.com> 3 +0000\n\nRelease",
+                PARENTS[1]
+            )
+            .as_bytes()]
+        );
+        assert_eq!(raw.header("x-custom"), Some(&b"value with  spaces "[..]));
+        assert_eq!(raw.message, b"\nMessage\n\nwith no trailing newline");
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_commits_that_wouldnt_round_trip() {
+        let parse = |body: String| RawCommit::parse(body.as_bytes());
+        let signatures = "author A <a@example.com> 1 +0000\ncommitter C <c@example.com> 2 +0000";
+
+        assert!(parse(format!("tree {}\n{}\n\nOK", TREE, signatures)).is_ok());
+        assert!(parse(format!(
+            "tree {}\n{}\n\nOK",
+            TREE.to_uppercase(),
+            signatures
+        ))
+        .is_err());
+        assert!(parse(format!("tree {}\n{}", TREE, signatures)).is_err());
+        assert!(parse(format!(
+            "tree {}\nauthor A <a@example.com> 01 +0000\ncommitter C <c@example.com> 2 \
+             +0000\n\nOK",
+            TREE
+        ))
+        .is_err());
+        assert!(parse(format!("{}\ntree {}\n\nOK", signatures, TREE)).is_err());
+    }
+
+    #[test]
+    fn round_trips_tags() -> Result<()> {
+        let repo = Repository::temporary()?;
+        let body = format!(
+            "object {}\ntype commit\ntag v1.0\ntagger T <t@example.com> 3 +0000\n\nRelease\n\
+             -----BEGIN PGP SIGNATURE-----\n\niQEzBAABCAAdFiEE\n-----END PGP SIGNATURE-----\n",
+            TREE
+        );
+        let id = repo.odb()?.write(ObjectType::Tag, body.as_bytes())?;
+        let raw = RawTag::parse(body.as_bytes())?;
+        assert_eq!(raw.to_bytes(), body.as_bytes());
+        assert_eq!(raw.id(), id);
+        assert!(raw.is_signed());
+
+        assert!(RawTag::parse(body.replace(TREE, &TREE.to_uppercase()).as_bytes()).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1340 @@
+//! Brute forcing commit IDs by searching over timestamps, optionally split
+//! into shards that can be run by separate processes (or machines) and merged
+//! afterwards, and tag IDs by searching over tagger timestamps and nonces.
+//!
+//! # Order
+//!
+//! A search over the timestamps from `min` to `max` considers every pair of
+//! author and committer timestamps where the committer's is no earlier than
+//! the author's: a triangle of candidates. They're enumerated diagonally, by
+//! increasing delta (the committer timestamp minus the author timestamp), and
+//! then by increasing author timestamp, so that commits committed soon after
+//! they were authored come first:
+//!
+//! ```text
+//! (min, min), (min + 1, min + 1), ..., (max, max),
+//! (min, min + 1), (min + 1, min + 2), ..., (max - 1, max),
+//! ...
+//! (min, max)
+//! ```
+//!
+//! The best candidate is the one whose ID is closest to the target prefix,
+//! by [`Candidate::score`], and if several are equally close, the first in
+//! this order. That doesn't depend on how many threads or processes the search
+//! is split between: there's no randomness, so a search is entirely determined
+//! by its base commit, target, and range, which act as its seed.
+//!
+//! Tags only have one timestamp, so a tag search is enumerated by nonce, and
+//! then by tagger timestamp, and ties go to the [`TagCandidate`] with the
+//! smallest nonce (a nonce of `0` meaning none at all).
+
+use {
+    crate::{
+        git2::{BruteForcedCommit, BruteForcedTag, CommitExt, MatchQuality, OidExt},
+        object::{RawCommit, RawTag},
+        signing::CommitSigner,
+    },
+    eyre::{bail, ensure, eyre, Result},
+    git2::{ObjectType, Oid, Repository},
+    rayon::iter::{IntoParallelIterator, ParallelIterator},
+    std::{
+        cmp::Ordering,
+        fmt, fs,
+        io::{ErrorKind, Write},
+        ops::RangeInclusive,
+        path::{Path, PathBuf},
+        str,
+        str::FromStr,
+        time::{Duration, Instant},
+    },
+    tempfile::NamedTempFile,
+    thousands::Separable,
+    tracing::{debug, info, instrument, trace},
+};
+
+/// The first line of a [`Job`] file.
+const JOB_MAGIC: &str = "save search job v2";
+/// The first line of a [`ShardOutcome`] file.
+const OUTCOME_MAGIC: &str = "save search outcome v1";
+/// The first line of a [`Checkpoint`] file.
+const CHECKPOINT_MAGIC: &str = "save search checkpoint v1";
+
+/// Candidates are hashed in batches of this many consecutive candidates on a
+/// diagonal.
+const BATCH: i64 = 256;
+/// Batches are searched in parallel in segments of this many, between which
+/// we may save a [`Checkpoint`].
+const SEGMENT: usize = 4_096;
+/// How often to save a [`Checkpoint`].
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+/// The most timestamps a search for a signed commit tries, for each of the
+/// author and committer. Every candidate has to be signed by running `gpg` or
+/// `ssh-keygen`, so this is only 36 candidates.
+pub const SIGNED_WINDOW: i64 = 8;
+
+/// A candidate commit found by a search.
+///
+/// Candidates are ordered from best to worst, by their `score` and then their
+/// position in the [search order](self#order), so the best of several is
+/// always their minimum, no matter how the search was split up.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Candidate {
+    /// The XOR of the ID with the target prefix, so smaller is closer.
+    pub score: Vec<u8>,
+    /// The committer timestamp.
+    pub committer_timestamp: i64,
+    /// The author timestamp.
+    pub author_timestamp: i64,
+    /// The ID of the candidate commit.
+    pub id: Oid,
+    /// The body of the candidate commit object.
+    pub body: Vec<u8>,
+}
+
+impl Candidate {
+    /// Builds the candidate with the given timestamps.
+    ///
+    /// # Errors
+    ///
+    /// If `signer` fails to sign the candidate.
+    pub fn new(
+        base: &RawCommit,
+        target_prefix: &[u8],
+        author_timestamp: i64,
+        committer_timestamp: i64,
+        signer: Option<&CommitSigner>,
+    ) -> Result<Self> {
+        let body = candidate_body(base, author_timestamp, committer_timestamp, signer)?;
+        let id = Oid::for_object("commit", &body);
+        Ok(Self {
+            score: score(id, target_prefix),
+            committer_timestamp,
+            author_timestamp,
+            id,
+            body,
+        })
+    }
+
+    /// The candidate's position in the [search order](self#order): the delta
+    /// between its timestamps, and then its author timestamp.
+    #[must_use]
+    pub const fn position(&self) -> (i64, i64) {
+        (
+            self.committer_timestamp - self.author_timestamp,
+            self.author_timestamp,
+        )
+    }
+
+    /// Returns the number of leading hex digits of the ID that match
+    /// `target_prefix`.
+    #[must_use]
+    pub fn matching_nibbles(&self, target_prefix: &[u8]) -> usize {
+        matching_nibbles(self.id, target_prefix)
+    }
+
+    /// Describes how well the candidate matches `target_prefix`, after
+    /// searching `candidates` candidates for a time of `requested_timestamp`.
+    #[must_use]
+    pub fn quality(
+        &self,
+        target_prefix: &[u8],
+        candidates: u64,
+        requested_timestamp: i64,
+    ) -> MatchQuality {
+        MatchQuality {
+            matching_bits: matching_bits(self.id, target_prefix),
+            matching_nibbles: self.matching_nibbles(target_prefix),
+            candidates,
+            author_timestamp: self.author_timestamp,
+            committer_timestamp: self.committer_timestamp,
+            offset_seconds: self.committer_timestamp - requested_timestamp,
+        }
+    }
+
+    /// Writes the candidate to `repo`'s object database. It's
+    /// [`BruteForcedCommit::Complete`] if at least `required_nibbles` leading
+    /// hex digits matched, according to `quality`.
+    ///
+    /// # Errors
+    ///
+    /// If the commit can't be written.
+    ///
+    /// # Panics
+    ///
+    /// If libgit2 doesn't agree with the candidate's ID.
+    pub fn write(
+        self,
+        repo: &Repository,
+        quality: MatchQuality,
+        required_nibbles: usize,
+    ) -> Result<BruteForcedCommit<'_>> {
+        let id = repo.odb()?.write(ObjectType::Commit, &self.body)?;
+        assert_eq!(self.id, id);
+
+        let commit = repo.find_commit(id)?;
+        assert_eq!(self.body, commit.to_bytes());
+
+        Ok(if quality.matching_nibbles >= required_nibbles {
+            debug!("Brute-forced a complete prefix match: {}", id);
+            BruteForcedCommit::Complete { commit, quality }
+        } else {
+            debug!("Brute-forced a partial prefix match: {}", id);
+            BruteForcedCommit::Incomplete { commit, quality }
+        })
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&self.score, self.position(), self.id, &self.body).cmp(&(
+            &other.score,
+            other.position(),
+            other.id,
+            &other.body,
+        ))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A candidate tag found by a search.
+///
+/// Like [`Candidate`]s, these are ordered from best to worst, by their `score`
+/// and then their position in the [search order](self#order).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TagCandidate {
+    /// The XOR of the ID with the target prefix, so smaller is closer.
+    pub score: Vec<u8>,
+    /// The nonce, or `0` for none.
+    pub nonce: u64,
+    /// The tagger timestamp.
+    pub tagger_timestamp: i64,
+    /// The ID of the candidate tag.
+    pub id: Oid,
+    /// The body of the candidate tag object.
+    pub body: Vec<u8>,
+}
+
+impl TagCandidate {
+    /// Describes how well the candidate matches `target_prefix`, after
+    /// searching `candidates` candidates for a time of `requested_timestamp`.
+    /// Its author and committer timestamps are both the tagger timestamp.
+    #[must_use]
+    pub fn quality(
+        &self,
+        target_prefix: &[u8],
+        candidates: u64,
+        requested_timestamp: i64,
+    ) -> MatchQuality {
+        MatchQuality {
+            matching_bits: matching_bits(self.id, target_prefix),
+            matching_nibbles: matching_nibbles(self.id, target_prefix),
+            candidates,
+            author_timestamp: self.tagger_timestamp,
+            committer_timestamp: self.tagger_timestamp,
+            offset_seconds: self.tagger_timestamp - requested_timestamp,
+        }
+    }
+
+    /// Writes the candidate to `repo`'s object database. It's
+    /// [`BruteForcedTag::Complete`] if at least `required_nibbles` leading
+    /// hex digits matched, according to `quality`.
+    ///
+    /// # Errors
+    ///
+    /// If the tag can't be written.
+    ///
+    /// # Panics
+    ///
+    /// If libgit2 doesn't agree with the candidate's ID.
+    pub fn write(
+        self,
+        repo: &Repository,
+        quality: MatchQuality,
+        required_nibbles: usize,
+    ) -> Result<BruteForcedTag<'_>> {
+        let id = repo.odb()?.write(ObjectType::Tag, &self.body)?;
+        assert_eq!(self.id, id);
+
+        let tag = repo.find_tag(id)?;
+        Ok(if quality.matching_nibbles >= required_nibbles {
+            debug!("Brute-forced a complete tag prefix match: {}", id);
+            BruteForcedTag::Complete { tag, quality }
+        } else {
+            debug!("Brute-forced a partial tag prefix match: {}", id);
+            BruteForcedTag::Incomplete { tag, quality }
+        })
+    }
+}
+
+impl Ord for TagCandidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&self.score, self.nonce, self.tagger_timestamp, self.id).cmp(&(
+            &other.score,
+            other.nonce,
+            other.tagger_timestamp,
+            other.id,
+        ))
+    }
+}
+
+impl PartialOrd for TagCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// One of `count` roughly-equal parts of a search, splitting the diagonals of
+/// the [search order](self#order) between them round-robin. Written as
+/// `index/count`, counting from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Shard {
+    /// Which shard this is, counting from 0.
+    pub index: u32,
+    /// How many shards the search is split into.
+    pub count: u32,
+}
+
+impl Shard {
+    /// The whole search, as a single shard.
+    pub const ALL: Self = Self { index: 0, count: 1 };
+
+    /// Every shard of a search split into `count`.
+    pub fn all(count: u32) -> impl Iterator<Item = Self> {
+        (0..count).map(move |index| Self { index, count })
+    }
+
+    /// Whether this shard includes the candidates whose committer timestamp
+    /// is `delta` seconds after their author timestamp.
+    #[must_use]
+    pub fn contains(self, delta: i64) -> bool {
+        delta.rem_euclid(i64::from(self.count)) == i64::from(self.index)
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index + 1, self.count)
+    }
+}
+
+impl FromStr for Shard {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (index, count) = s
+            .split_once('/')
+            .ok_or_else(|| eyre!("shard must be written like 1/4, not {:?}", s))?;
+        let (index, count): (u32, u32) = (index.parse()?, count.parse()?);
+        ensure!(
+            (1..=count).contains(&index),
+            "shard must be between 1/{} and {}/{}, not {:?}",
+            count,
+            count,
+            count,
+            s
+        );
+        Ok(Self {
+            index: index - 1,
+            count,
+        })
+    }
+}
+
+/// A search for a commit ID starting with `target_prefix`, by trying every
+/// pair of author and committer timestamps (with the committer's no earlier
+/// than the author's) between `min_timestamp` and `max_timestamp`, inclusive.
+///
+/// Jobs can be written to files with [`Job::to_bytes`], split between workers
+/// with [`Job::run`], and their [`ShardOutcome`]s combined with
+/// [`Job::merge`], producing the same commit as searching in one process.
+/// Signed commits can't be searched this way, since every worker would need
+/// the signing key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Job {
+    /// The commit to search from. Only its timestamps are changed.
+    pub base: RawCommit,
+    /// The target commit ID prefix.
+    pub target_prefix: Vec<u8>,
+    /// The earliest timestamp to try.
+    pub min_timestamp: i64,
+    /// The latest timestamp to try.
+    pub max_timestamp: i64,
+    /// How many leading hex digits of `target_prefix` must match for the
+    /// result to be [`BruteForcedCommit::Complete`].
+    pub required_nibbles: usize,
+}
+
+impl Job {
+    /// Serializes this into a job file: a few headers, a blank line, and then
+    /// the base commit object.
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!(
+            "{}\ntarget {}\nmin-timestamp {}\nmax-timestamp {}\nrequired-nibbles {}\n\n",
+            JOB_MAGIC,
+            hex::encode(&self.target_prefix),
+            self.min_timestamp,
+            self.max_timestamp,
+            self.required_nibbles
+        )
+        .into_bytes();
+        bytes.extend(self.base.to_bytes());
+        bytes
+    }
+
+    /// Parses a job file written by [`Job::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// If the file is malformed.
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let end = bytes
+            .windows(2)
+            .position(|window| window == b"\n\n")
+            .ok_or_else(|| eyre!("search job has no blank line after its headers"))?;
+        let headers = Headers::parse(&bytes[..end], JOB_MAGIC)?;
+
+        Ok(Self {
+            base: RawCommit::parse(&bytes[end + 2..])?,
+            target_prefix: hex::decode(headers.get("target")?)?,
+            min_timestamp: headers.get("min-timestamp")?.parse()?,
+            max_timestamp: headers.get("max-timestamp")?.parse()?,
+            required_nibbles: headers.get("required-nibbles")?.parse()?,
+        })
+    }
+
+    /// The ID of this job, which is the ID its job file would have as a Git
+    /// blob.
+    #[must_use]
+    pub fn id(&self) -> Oid {
+        Oid::for_object("blob", &self.to_bytes())
+    }
+
+    /// Searches one shard of this job.
+    ///
+    /// # Errors
+    ///
+    /// If the search fails.
+    #[instrument(level = "debug", skip(self))]
+    pub fn run(&self, shard: Shard) -> Result<ShardOutcome> {
+        info!(
+            "Searching shard {} of job {} for {}.",
+            shard,
+            self.id(),
+            hex::encode(&self.target_prefix)
+        );
+        let best = best_candidate(
+            &self.base,
+            &self.target_prefix,
+            self.min_timestamp,
+            self.max_timestamp,
+            None,
+            shard,
+            None,
+        )?;
+
+        Ok(ShardOutcome {
+            job: self.id(),
+            shard,
+            best: best.map(|best| (best.author_timestamp, best.committer_timestamp, best.id)),
+        })
+    }
+
+    /// Combines the outcomes of every shard of this job into its best
+    /// candidate.
+    ///
+    /// # Errors
+    ///
+    /// If any outcome is from another job, or disagrees with what we'd
+    /// compute ourselves, or if any shard is missing or repeated.
+    pub fn merge(&self, outcomes: &[ShardOutcome]) -> Result<Candidate> {
+        let job = self.id();
+        let count = match outcomes.first() {
+            Some(outcome) => outcome.shard.count,
+            None => bail!("No search outcomes to merge."),
+        };
+
+        let mut shards = outcomes
+            .iter()
+            .map(|outcome| outcome.shard)
+            .collect::<Vec<_>>();
+        shards.sort();
+        if !shards.iter().copied().eq(Shard::all(count)) {
+            bail!(
+                "Expected one outcome for each of {} shards, but got: {}",
+                count,
+                shards
+                    .iter()
+                    .map(Shard::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        let mut best: Option<Candidate> = None;
+        for outcome in outcomes {
+            ensure!(
+                outcome.job == job,
+                "Outcome for shard {} is from job {}, not {}.",
+                outcome.shard,
+                outcome.job,
+                job
+            );
+            if let Some((author_timestamp, committer_timestamp, id)) = outcome.best {
+                ensure!(
+                    self.min_timestamp <= author_timestamp
+                        && author_timestamp <= committer_timestamp
+                        && committer_timestamp <= self.max_timestamp
+                        && outcome
+                            .shard
+                            .contains(committer_timestamp - author_timestamp),
+                    "Outcome for shard {} has timestamps outside of it.",
+                    outcome.shard
+                );
+                let candidate = Candidate::new(
+                    &self.base,
+                    &self.target_prefix,
+                    author_timestamp,
+                    committer_timestamp,
+                    None,
+                )?;
+                ensure!(
+                    candidate.id == id,
+                    "Outcome for shard {} claims its timestamps produce {}, but they produce {}.",
+                    outcome.shard,
+                    id,
+                    candidate.id
+                );
+                best = best.into_iter().chain([candidate]).min();
+            }
+        }
+
+        best.ok_or_else(|| eyre!("No shard found any candidates."))
+    }
+}
+
+/// The best candidate found in one [`Shard`] of a [`Job`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ShardOutcome {
+    /// The ID of the job.
+    pub job: Oid,
+    /// The shard that was searched.
+    pub shard: Shard,
+    /// The author timestamp, committer timestamp, and ID of the best
+    /// candidate, if the shard had any.
+    pub best: Option<(i64, i64, Oid)>,
+}
+
+impl ShardOutcome {
+    /// Serializes this into an outcome file.
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut text = format!(
+            "{}\njob {}\nshard {}\n",
+            OUTCOME_MAGIC, self.job, self.shard
+        );
+        if let Some((author_timestamp, committer_timestamp, id)) = self.best {
+            text += &format!(
+                "author-timestamp {}\ncommitter-timestamp {}\nid {}\n",
+                author_timestamp, committer_timestamp, id
+            );
+        }
+        text.into_bytes()
+    }
+
+    /// Parses an outcome file written by [`ShardOutcome::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// If the file is malformed.
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let headers = Headers::parse(bytes.strip_suffix(b"\n").unwrap_or(bytes), OUTCOME_MAGIC)?;
+        Ok(Self {
+            job: Oid::from_str(headers.get("job")?)?,
+            shard: headers.get("shard")?.parse()?,
+            best: headers.best()?,
+        })
+    }
+}
+
+/// The progress of an interrupted search, saved periodically by
+/// [`best_candidate`] so that it can pick up where it left off.
+///
+/// Checkpoints are stored in a directory (`.git/save/` for
+/// [`RepositoryExt::brute_force_commit`]) with a file for each template: the
+/// base commit, ignoring its timestamps. A checkpoint for a different target,
+/// range, or shard is ignored, and overwritten once the new search saves its
+/// own.
+///
+/// [`RepositoryExt::brute_force_commit`]: crate::git2::RepositoryExt::brute_force_commit
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Checkpoint {
+    /// The ID of the base commit with both timestamps set to zero.
+    pub template: Oid,
+    /// The target commit ID prefix.
+    pub target_prefix: Vec<u8>,
+    /// The earliest timestamp in the search.
+    pub min_timestamp: i64,
+    /// The latest timestamp in the search.
+    pub max_timestamp: i64,
+    /// The shard being searched.
+    pub shard: Shard,
+    /// How many batches of candidates in the shard have been fully explored.
+    pub explored: u64,
+    /// The author timestamp, committer timestamp, and ID of the best
+    /// candidate so far, if any.
+    pub best: Option<(i64, i64, Oid)>,
+}
+
+impl Checkpoint {
+    /// Returns the template ID for a base commit.
+    #[must_use]
+    pub fn template(base: &RawCommit) -> Oid {
+        let mut template = base.clone();
+        template.author.seconds = 0;
+        template.committer.seconds = 0;
+        template.id()
+    }
+
+    /// Loads the checkpoint in `dir` for `base`'s template and
+    /// `target_prefix`, if there is one.
+    ///
+    /// # Errors
+    ///
+    /// If the checkpoint exists but can't be read.
+    pub fn load(dir: &Path, base: &RawCommit, target_prefix: &[u8]) -> Result<Option<Self>> {
+        let template = Self::template(base);
+        let bytes = match fs::read(Self::path(dir, template)) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let checkpoint = Self::parse(&bytes)?;
+        if checkpoint.template == template && checkpoint.target_prefix == target_prefix {
+            Ok(Some(checkpoint))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Saves this checkpoint in `dir`, replacing any other for its template.
+    ///
+    /// # Errors
+    ///
+    /// If the checkpoint can't be written.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        let mut file = NamedTempFile::new_in(dir)?;
+        file.write_all(&self.to_bytes())?;
+        file.persist(Self::path(dir, self.template))?;
+        trace!("Saved checkpoint after {} batches.", self.explored);
+        Ok(())
+    }
+
+    /// Removes the checkpoint in `dir` for `template`, if there is one.
+    ///
+    /// # Errors
+    ///
+    /// If the checkpoint exists but can't be removed.
+    pub fn remove(dir: &Path, template: Oid) -> Result<()> {
+        match fs::remove_file(Self::path(dir, template)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Serializes this into a checkpoint file.
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut text = format!(
+            "{}\ntemplate {}\ntarget {}\nmin-timestamp {}\nmax-timestamp {}\nshard {}\nexplored {}\n",
+            CHECKPOINT_MAGIC,
+            self.template,
+            hex::encode(&self.target_prefix),
+            self.min_timestamp,
+            self.max_timestamp,
+            self.shard,
+            self.explored
+        );
+        if let Some((author_timestamp, committer_timestamp, id)) = self.best {
+            text += &format!(
+                "author-timestamp {}\ncommitter-timestamp {}\nid {}\n",
+                author_timestamp, committer_timestamp, id
+            );
+        }
+        text.into_bytes()
+    }
+
+    /// Parses a checkpoint file written by [`Checkpoint::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// If the file is malformed.
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let headers = Headers::parse(bytes.strip_suffix(b"\n").unwrap_or(bytes), CHECKPOINT_MAGIC)?;
+        Ok(Self {
+            template: Oid::from_str(headers.get("template")?)?,
+            target_prefix: hex::decode(headers.get("target")?)?,
+            min_timestamp: headers.get("min-timestamp")?.parse()?,
+            max_timestamp: headers.get("max-timestamp")?.parse()?,
+            shard: headers.get("shard")?.parse()?,
+            explored: headers.get("explored")?.parse()?,
+            best: headers.best()?,
+        })
+    }
+
+    /// The path of the checkpoint in `dir` for `template`.
+    fn path(dir: &Path, template: Oid) -> PathBuf {
+        dir.join(format!("checkpoint-{}", template))
+    }
+}
+
+/// The `name value` lines at the start of a job, outcome, or checkpoint
+/// file.
+struct Headers<'a>(Vec<(&'a str, &'a str)>);
+
+impl<'a> Headers<'a> {
+    /// Parses the header lines in `bytes`, which must start with `magic`.
+    fn parse(bytes: &'a [u8], magic: &str) -> Result<Self> {
+        let mut lines = str::from_utf8(bytes)?.lines();
+        ensure!(
+            lines.next() == Some(magic),
+            "Expected a file starting with {:?}.",
+            magic
+        );
+        Ok(Self(
+            lines
+                .map(|line| {
+                    line.split_once(' ')
+                        .ok_or_else(|| eyre!("header has no value: {:?}", line))
+                })
+                .collect::<Result<_>>()?,
+        ))
+    }
+
+    /// Returns the author timestamp, committer timestamp, and ID of a
+    /// candidate, if there's an `id` header.
+    fn best(&self) -> Result<Option<(i64, i64, Oid)>> {
+        if !self.0.iter().any(|(key, _)| *key == "id") {
+            return Ok(None);
+        }
+        Ok(Some((
+            self.get("author-timestamp")?.parse()?,
+            self.get("committer-timestamp")?.parse()?,
+            Oid::from_str(self.get("id")?)?,
+        )))
+    }
+
+    /// Returns the value of the header with this name.
+    fn get(&self, name: &str) -> Result<&'a str> {
+        self.0
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| *value)
+            .ok_or_else(|| eyre!("missing {:?} header", name))
+    }
+}
+
+/// How to keep searching when the best candidate in a window doesn't match
+/// enough of the target: by doubling the window (while keeping its start),
+/// which quadruples the number of candidates, until one matches or we're out
+/// of time.
+///
+/// Each wider window is searched from the beginning, but since it has four
+/// times as many candidates as the last, that only repeats about a quarter of
+/// the work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Widening {
+    /// How many leading hex digits of the target must match.
+    pub required_nibbles: usize,
+    /// How long to keep searching. We don't start on a wider window unless
+    /// it's expected to finish within this.
+    pub budget: Duration,
+}
+
+/// An estimate of how difficult it is to find a commit ID with a given prefix.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    /// The length of the target prefix, in bits.
+    pub prefix_bits: u32,
+    /// The number of timestamps in the search window.
+    pub window: i64,
+    /// The number of candidates in the window.
+    pub candidates: u64,
+    /// How many candidates we can hash per second.
+    pub hash_rate: f64,
+    /// The probability that any candidate in the window matches the prefix.
+    pub probability: f64,
+    /// How long it takes to search the entire window, in seconds.
+    pub window_seconds: f64,
+    /// How long it takes to find a match on average, in seconds, if the
+    /// window were unlimited.
+    pub expected_seconds: f64,
+}
+
+impl Estimate {
+    /// Estimates the difficulty of finding a commit ID starting with
+    /// `prefix_bits` bits of a target, searching a window of `window`
+    /// timestamps (for both the author and committer), at a `hash_rate`
+    /// measured by [`Estimate::calibrate`].
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn new(prefix_bits: u32, window: i64, hash_rate: f64) -> Self {
+        let window = window.max(0);
+        let candidates = candidates_in_window(window);
+        let match_probability = 0.5_f64.powi(prefix_bits.try_into().unwrap_or(i32::MAX));
+
+        Self {
+            prefix_bits,
+            window,
+            candidates,
+            hash_rate,
+            // 1 - (1 - p)^n, accurately even when p is tiny
+            probability: -((candidates as f64) * (-match_probability).ln_1p()).exp_m1(),
+            window_seconds: candidates as f64 / hash_rate,
+            expected_seconds: 1.0 / match_probability / hash_rate,
+        }
+    }
+
+    /// Measures how many candidates based on `base` this machine can hash per
+    /// second, using every thread, by searching for about `duration`.
+    ///
+    /// # Errors
+    ///
+    /// If the search fails.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn calibrate(base: &RawCommit, duration: Duration) -> Result<f64> {
+        // a window with about 64K candidates
+        const WINDOW: i64 = 362;
+
+        let start = Instant::now();
+        let mut candidates = 0_u64;
+        let mut min_timestamp = base.author.seconds;
+        while start.elapsed() < duration {
+            best_candidate(
+                base,
+                &[],
+                min_timestamp,
+                min_timestamp + WINDOW - 1,
+                None,
+                Shard::ALL,
+                None,
+            )?;
+            candidates += candidates_in_window(WINDOW);
+            min_timestamp += WINDOW;
+        }
+        Ok(candidates as f64 / start.elapsed().as_secs_f64())
+    }
+}
+
+impl fmt::Display for Estimate {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Hash rate:   {} candidates per second",
+            (self.hash_rate.round() as u64).separate_with_commas()
+        )?;
+        writeln!(
+            f,
+            "Window:      {} seconds, {} candidates, searched in {}",
+            self.window.separate_with_commas(),
+            self.candidates.separate_with_commas(),
+            human_duration(self.window_seconds)
+        )?;
+        writeln!(
+            f,
+            "Chance of a match in the window: {:.4}% (1 in {})",
+            self.probability * 100.0,
+            human_count(1.0 / self.probability)
+        )?;
+        write!(
+            f,
+            "Expected time to find a match:   {}",
+            human_duration(self.expected_seconds)
+        )
+    }
+}
+
+/// Formats a count with thousands separators, or in scientific notation if
+/// it's enormous.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn human_count(count: f64) -> String {
+    if count < 1e15 {
+        (count.round() as u64).separate_with_commas()
+    } else {
+        format!("{:.2e}", count)
+    }
+}
+
+/// Formats a number of seconds in the largest suitable unit.
+fn human_duration(seconds: f64) -> String {
+    let units = [
+        ("years", 365.25 * 24.0 * 60.0 * 60.0),
+        ("days", 24.0 * 60.0 * 60.0),
+        ("hours", 60.0 * 60.0),
+        ("minutes", 60.0),
+        ("seconds", 1.0),
+    ];
+    for (unit, length) in units {
+        if seconds >= 1e6 * length {
+            return format!("{} {}", human_count(seconds / length), unit);
+        } else if seconds >= length {
+            return format!("{:.1} {}", seconds / length, unit);
+        }
+    }
+    format!("{:.1} milliseconds", seconds * 1000.0)
+}
+
+/// Returns the best candidate in one shard of a search, or `None` if the
+/// shard is empty. See [`Job`].
+///
+/// If `signer` is specified, every candidate is signed.
+///
+/// If `checkpoints` is specified, a [`Checkpoint`] is saved in that directory
+/// every few seconds, and the search resumes from one that's already there
+/// for the same search. It's removed once the search is complete.
+///
+/// # Errors
+///
+/// If signing any candidate fails, or if a checkpoint can't be read or
+/// written.
+pub fn best_candidate(
+    base: &RawCommit,
+    target_prefix: &[u8],
+    min_timestamp: i64,
+    max_timestamp: i64,
+    signer: Option<&CommitSigner>,
+    shard: Shard,
+    checkpoints: Option<&Path>,
+) -> Result<Option<Candidate>> {
+    let mut checkpoint = Checkpoint {
+        template: Checkpoint::template(base),
+        target_prefix: target_prefix.to_vec(),
+        min_timestamp,
+        max_timestamp,
+        shard,
+        explored: 0,
+        best: None,
+    };
+
+    let mut best = None;
+    if let Some(dir) = checkpoints {
+        match Checkpoint::load(dir, base, target_prefix)? {
+            Some(saved)
+                if (saved.min_timestamp, saved.max_timestamp, saved.shard)
+                    == (min_timestamp, max_timestamp, shard) =>
+            {
+                info!(
+                    "Resuming search from a checkpoint after {} batches.",
+                    saved.explored
+                );
+                if let Some((author_timestamp, committer_timestamp, id)) = saved.best {
+                    let candidate = Candidate::new(
+                        base,
+                        target_prefix,
+                        author_timestamp,
+                        committer_timestamp,
+                        signer,
+                    )?;
+                    ensure!(candidate.id == id, "Checkpoint doesn't match this search.");
+                    best = Some(candidate);
+                }
+                checkpoint = saved;
+            },
+            _ => {},
+        }
+    }
+
+    // each batch is a diagonal, and the first author timestamp in it
+    let last_delta = max_timestamp - min_timestamp;
+    let mut batches = (0..=last_delta)
+        .filter(|&delta| shard.contains(delta))
+        .flat_map(|delta| {
+            (0..=(last_delta - delta) / BATCH)
+                .map(move |batch| (delta, min_timestamp + batch * BATCH))
+        })
+        .skip(checkpoint.explored.try_into()?);
+
+    let mut last_saved = Instant::now();
+    loop {
+        let segment = batches.by_ref().take(SEGMENT).collect::<Vec<_>>();
+        if segment.is_empty() {
+            break;
+        }
+        let explored = segment.len();
+
+        let segment_best = segment
+            .into_par_iter()
+            .map(|(delta, first)| -> Result<Option<Candidate>> {
+                let last = first.saturating_add(BATCH - 1).min(max_timestamp - delta);
+
+                let bodies = (first..=last)
+                    .map(|author_timestamp| {
+                        candidate_body(base, author_timestamp, author_timestamp + delta, signer)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let ids = Oid::for_objects("commit", &bodies);
+
+                Ok((first..=last)
+                    .zip(ids)
+                    .zip(bodies)
+                    .map(|((author_timestamp, id), body)| Candidate {
+                        score: score(id, target_prefix),
+                        committer_timestamp: author_timestamp + delta,
+                        author_timestamp,
+                        id,
+                        body,
+                    })
+                    .min())
+            })
+            .filter_map(Result::transpose)
+            .try_reduce_with(|a, b| Ok(a.min(b)))
+            .transpose()?;
+        best = best.into_iter().chain(segment_best).min();
+
+        checkpoint.explored += u64::try_from(explored)?;
+        if let Some(dir) = checkpoints {
+            if last_saved.elapsed() >= CHECKPOINT_INTERVAL {
+                checkpoint.best = best
+                    .as_ref()
+                    .map(|best| (best.author_timestamp, best.committer_timestamp, best.id));
+                checkpoint.save(dir)?;
+                last_saved = Instant::now();
+            }
+        }
+    }
+
+    if let Some(dir) = checkpoints {
+        Checkpoint::remove(dir, checkpoint.template)?;
+    }
+
+    Ok(best)
+}
+
+/// Returns the best [`TagCandidate`] based on `base`, closest to
+/// `target_prefix`, with each of the tagger `timestamps` and `nonces`, or
+/// [`None`] if either range is empty.
+#[instrument(level = "debug", skip(base))]
+pub fn best_tag_candidate(
+    base: &RawTag,
+    target_prefix: &[u8],
+    timestamps: RangeInclusive<i64>,
+    nonces: RangeInclusive<u64>,
+) -> Option<TagCandidate> {
+    let (min_timestamp, max_timestamp) = (*timestamps.start(), *timestamps.end());
+    if min_timestamp > max_timestamp {
+        return None;
+    }
+
+    // each batch is a nonce, and the first tagger timestamp in it
+    let mut batches = nonces.flat_map(|nonce| {
+        (0..=(max_timestamp - min_timestamp) / BATCH)
+            .map(move |batch| (nonce, min_timestamp + batch * BATCH))
+    });
+
+    let mut best = None;
+    loop {
+        let segment = batches.by_ref().take(SEGMENT).collect::<Vec<_>>();
+        if segment.is_empty() {
+            break;
+        }
+
+        let segment_best = segment
+            .into_par_iter()
+            .filter_map(|(nonce, first)| {
+                let last = first.saturating_add(BATCH - 1).min(max_timestamp);
+
+                let bodies = (first..=last)
+                    .map(|tagger_timestamp| {
+                        let mut candidate = base.clone();
+                        if let Some(ref mut tagger) = candidate.tagger {
+                            tagger.seconds = tagger_timestamp;
+                        }
+                        candidate.set_nonce(nonce);
+                        candidate.to_bytes()
+                    })
+                    .collect::<Vec<_>>();
+                let ids = Oid::for_objects("tag", &bodies);
+
+                (first..=last)
+                    .zip(ids)
+                    .zip(bodies)
+                    .map(|((tagger_timestamp, id), body)| TagCandidate {
+                        score: score(id, target_prefix),
+                        nonce,
+                        tagger_timestamp,
+                        id,
+                        body,
+                    })
+                    .min()
+            })
+            .min();
+        best = best.into_iter().chain(segment_best).min();
+    }
+
+    best
+}
+
+/// Returns the best [`Candidate`] based on `base`, closest to
+/// `target_prefix`, keeping its author timestamp and trying each of the
+/// committer `timestamps`, or [`None`] if the range is empty. Ties go to the
+/// earliest committer timestamp.
+#[instrument(level = "debug", skip(base))]
+pub fn best_committer_candidate(
+    base: &RawCommit,
+    target_prefix: &[u8],
+    timestamps: RangeInclusive<i64>,
+) -> Option<Candidate> {
+    let (min_timestamp, max_timestamp) = (*timestamps.start(), *timestamps.end());
+    if min_timestamp > max_timestamp {
+        return None;
+    }
+    let author_timestamp = base.author.seconds;
+
+    // each batch is identified by its first committer timestamp
+    (0..=(max_timestamp - min_timestamp) / BATCH)
+        .into_par_iter()
+        .filter_map(|batch| {
+            let first = min_timestamp + batch * BATCH;
+            let last = first.saturating_add(BATCH - 1).min(max_timestamp);
+
+            let bodies = (first..=last)
+                .map(|committer_timestamp| {
+                    let mut candidate = base.clone();
+                    candidate.committer.seconds = committer_timestamp;
+                    candidate.to_bytes()
+                })
+                .collect::<Vec<_>>();
+            let ids = Oid::for_objects("commit", &bodies);
+
+            (first..=last)
+                .zip(ids)
+                .zip(bodies)
+                .map(|((committer_timestamp, id), body)| Candidate {
+                    score: score(id, target_prefix),
+                    committer_timestamp,
+                    author_timestamp,
+                    id,
+                    body,
+                })
+                .min()
+        })
+        .min()
+}
+
+/// Returns the body of the candidate with the given timestamps.
+fn candidate_body(
+    base: &RawCommit,
+    author_timestamp: i64,
+    committer_timestamp: i64,
+    signer: Option<&CommitSigner>,
+) -> Result<Vec<u8>> {
+    let mut candidate = base.clone();
+    candidate.author.seconds = author_timestamp;
+    candidate.committer.seconds = committer_timestamp;
+    let body = candidate.to_bytes();
+
+    match signer {
+        Some(signer) => signer.sign_commit(&body),
+        None => Ok(body),
+    }
+}
+
+/// Returns the number of candidates in a search of `window` timestamps: every
+/// pair of author and committer timestamps with the committer's no earlier,
+/// or `window * (window + 1) / 2`.
+#[must_use]
+pub fn candidates_in_window(window: i64) -> u64 {
+    let window = u64::try_from(window).unwrap_or_default();
+    if window % 2 == 0 {
+        (window / 2).saturating_mul(window + 1)
+    } else {
+        window.saturating_mul(window / 2 + 1)
+    }
+}
+
+/// Returns the number of leading hex digits of `id` that match
+/// `target_prefix`.
+#[must_use]
+pub fn matching_nibbles(id: Oid, target_prefix: &[u8]) -> usize {
+    usize::try_from(matching_bits(id, target_prefix) / 4).unwrap_or(usize::MAX)
+}
+
+/// Returns the number of leading bits of `id` that match `target_prefix`.
+#[must_use]
+pub fn matching_bits(id: Oid, target_prefix: &[u8]) -> u32 {
+    let mut bits = 0;
+    for (a, b) in id.as_bytes().iter().zip(target_prefix) {
+        bits += (a ^ b).leading_zeros();
+        if a != b {
+            break;
+        }
+    }
+    bits
+}
+
+/// Returns the XOR of `id` with `target_prefix`, which is smaller the closer
+/// they are.
+fn score(id: Oid, target_prefix: &[u8]) -> Vec<u8> {
+    id.as_bytes()
+        .iter()
+        .zip(target_prefix.iter())
+        .map(|(a, b)| a ^ b)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, git2::Signature};
+
+    /// A search of 32 timestamps (528 candidates) for `abcd`.
+    fn job() -> Result<Job> {
+        let signature = Signature::new(
+            "A U Thor",
+            "author@example.com",
+            &git2::Time::new(1_600_000_000, 0),
+        )?;
+        let base = RawCommit::new(
+            Oid::for_object("tree", &[]),
+            &[Oid::for_object("commit", b"parent")],
+            &signature,
+            &signature,
+            "r1/abcd/1234\n",
+        );
+        Ok(Job {
+            base,
+            target_prefix: vec![0xAB, 0xCD],
+            min_timestamp: 1_600_000_000,
+            max_timestamp: 1_600_000_031,
+            required_nibbles: 3,
+        })
+    }
+
+    #[test]
+    fn counts_candidates_in_windows() {
+        assert_eq!(candidates_in_window(-1), 0);
+        assert_eq!(candidates_in_window(0), 0);
+        assert_eq!(candidates_in_window(1), 1);
+        assert_eq!(candidates_in_window(2), 3);
+        assert_eq!(candidates_in_window(64), 2_080);
+        assert_eq!(candidates_in_window(362), 65_703);
+        assert_eq!(candidates_in_window(i64::MAX), u64::MAX);
+        assert_eq!(Estimate::new(0, 64, 1.0).candidates, 2_080);
+    }
+
+    #[test]
+    fn committer_searches_keep_the_author_timestamp() -> Result<()> {
+        let job = job()?;
+        let mut base = job.base.clone();
+        base.author.seconds = 1_500_000_000;
+        let timestamps = 1_600_000_000..=1_600_000_999;
+
+        let best = best_committer_candidate(&base, &job.target_prefix, timestamps.clone()).unwrap();
+        assert_eq!(best.author_timestamp, base.author.seconds);
+        assert!(timestamps.contains(&best.committer_timestamp));
+        assert_eq!(
+            best,
+            timestamps
+                .map(|committer_timestamp| {
+                    Candidate::new(
+                        &base,
+                        &job.target_prefix,
+                        base.author.seconds,
+                        committer_timestamp,
+                        None,
+                    )
+                })
+                .collect::<Result<Vec<_>>>()?
+                .into_iter()
+                .min()
+                .unwrap()
+        );
+        assert_eq!(
+            best_committer_candidate(&base, &job.target_prefix, RangeInclusive::new(1, 0)),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn jobs_round_trip() -> Result<()> {
+        let job = job()?;
+        assert_eq!(Job::parse(&job.to_bytes())?, job);
+        assert!(Job::parse(&job.to_bytes()[..job.to_bytes().len() / 2]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sharded_jobs_match_a_single_search() -> Result<()> {
+        let job = job()?;
+        let expected = best_candidate(
+            &job.base,
+            &job.target_prefix,
+            job.min_timestamp,
+            job.max_timestamp,
+            None,
+            Shard::ALL,
+            None,
+        )?
+        .unwrap();
+
+        for count in [1, 3, 7] {
+            // each shard is run by a separate "worker", from the job file
+            let outcomes = Shard::all(count)
+                .map(|shard| -> Result<ShardOutcome> {
+                    let worker = Job::parse(&job.to_bytes())?;
+                    ShardOutcome::parse(&worker.run(shard)?.to_bytes())
+                })
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(job.merge(&outcomes)?, expected, "with {} shards", count);
+
+            let mut reversed = outcomes.clone();
+            reversed.reverse();
+            assert_eq!(job.merge(&reversed)?, expected);
+            if count > 1 {
+                assert!(job.merge(&outcomes[1..]).is_err());
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn searches_dont_depend_on_the_number_of_threads() -> Result<()> {
+        let job = job()?;
+        let search = || {
+            best_candidate(
+                &job.base,
+                &job.target_prefix,
+                job.min_timestamp,
+                // enough candidates for many batches
+                job.min_timestamp + 255,
+                None,
+                Shard::ALL,
+                None,
+            )
+        };
+
+        let single = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()?
+            .install(search)?;
+        assert!(single.is_some());
+        for threads in [2, 4, 8] {
+            let parallel = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()?
+                .install(search)?;
+            assert_eq!(parallel, single, "with {} threads", threads);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+//! Signing commits with `gpg` or `ssh-keygen`, the way Git does.
+
+use {
+    eyre::{bail, eyre, Result, WrapErr},
+    git2::Config,
+    std::{
+        io::Write,
+        path::PathBuf,
+        process::{Command, Stdio},
+    },
+    tempfile::NamedTempFile,
+    tracing::{debug, instrument, trace},
+};
+
+/// The SSH signature namespace Git uses for commits.
+const SSH_NAMESPACE: &str = "git";
+
+/// Produces the `gpgsig` header for commits, using one of the signature
+/// formats supported by Git's `gpg.format` configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommitSigner {
+    /// `gpg.format = openpgp`: sign with `gpg.program` (default `gpg`).
+    OpenPgp {
+        /// The program to run, as configured in `gpg.program`.
+        program: String,
+        /// The key to sign with. If this is `None`, `gpg` will use its
+        /// default key.
+        key: Option<String>,
+    },
+    /// `gpg.format = ssh`: sign with `gpg.ssh.program` (default
+    /// `ssh-keygen`).
+    Ssh {
+        /// The program to run, as configured in `gpg.ssh.program`.
+        program: String,
+        /// The path to a private key, a public key held by `ssh-agent`, or a
+        /// literal public key prefixed with `key::`.
+        key: String,
+    },
+}
+
+impl CommitSigner {
+    /// Returns the signer configured in Git, if `commit.gpgsign` is enabled.
+    ///
+    /// # Errors
+    ///
+    /// If signing is enabled but the configuration isn't usable.
+    #[instrument(level = "debug", skip_all)]
+    pub fn configured(config: &Config) -> Result<Option<Self>> {
+        if config.get_bool("commit.gpgsign").unwrap_or(false) {
+            Ok(Some(Self::from_config(config, None)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns a signer from the Git configuration's `gpg.format` and
+    /// `user.signingkey`, regardless of whether `commit.gpgsign` is enabled.
+    ///
+    /// If `key` is specified, it's used instead of `user.signingkey`.
+    ///
+    /// # Errors
+    ///
+    /// If the configured `gpg.format` isn't supported, or if an SSH signer has
+    /// no key.
+    #[instrument(level = "debug", skip(config))]
+    pub fn from_config(config: &Config, key: Option<&str>) -> Result<Self> {
+        let key = key
+            .map(ToString::to_string)
+            .or_else(|| config.get_string("user.signingkey").ok())
+            .filter(|key| !key.is_empty());
+        let format = config
+            .get_string("gpg.format")
+            .unwrap_or_else(|_| "openpgp".to_string());
+
+        let signer = match format.as_str() {
+            "openpgp" => Self::OpenPgp {
+                program: config
+                    .get_string("gpg.openpgp.program")
+                    .or_else(|_| config.get_string("gpg.program"))
+                    .unwrap_or_else(|_| "gpg".to_string()),
+                key,
+            },
+            "ssh" => Self::Ssh {
+                program: config
+                    .get_string("gpg.ssh.program")
+                    .unwrap_or_else(|_| "ssh-keygen".to_string()),
+                key: key.ok_or_else(|| {
+                    eyre!("gpg.format is ssh, but no user.signingkey is configured")
+                })?,
+            },
+            other => bail!("Unsupported gpg.format for signing commits: {:?}", other),
+        };
+
+        debug!("Using commit signer: {:?}", signer);
+        Ok(signer)
+    }
+
+    /// Returns a detached ASCII-armored signature of `payload`.
+    ///
+    /// # Errors
+    ///
+    /// If the signing program can't be run or reports a failure.
+    #[instrument(level = "trace", skip(payload))]
+    pub fn sign(&self, payload: &[u8]) -> Result<String> {
+        match self {
+            Self::OpenPgp { program, key } => {
+                let mut command = Command::new(program);
+                command.args(["--status-fd=2", "-bsa"]);
+                if let Some(key) = key {
+                    command.args(["-u", key]);
+                }
+                let (signature, stderr) = run_signer(command, payload)?;
+                if !stderr.contains("[GNUPG:] SIG_CREATED ") {
+                    bail!("{} failed to sign the commit:\n{}", program, stderr);
+                }
+                Ok(signature)
+            },
+            Self::Ssh { program, key } => {
+                let mut command = Command::new(program);
+                command.args(["-Y", "sign", "-n", SSH_NAMESPACE]);
+
+                // Literal public keys need to be written to a file, and
+                // their private halves must be available from `ssh-agent`.
+                let _literal_key_file;
+                if let Some(literal) = key
+                    .strip_prefix("key::")
+                    .or_else(|| Some(key.as_str()).filter(|key| key.starts_with("ssh-")))
+                {
+                    let mut file = NamedTempFile::new()?;
+                    writeln!(file, "{}", literal)?;
+                    command.arg("-U").arg("-f").arg(file.path());
+                    _literal_key_file = file;
+                } else {
+                    command.arg("-f").arg(expand_home(key));
+                }
+
+                let (signature, _stderr) = run_signer(command, payload)?;
+                Ok(signature)
+            },
+        }
+    }
+
+    /// Returns a copy of the raw commit object `body` with a `gpgsig` header
+    /// containing a signature of the original `body`.
+    ///
+    /// # Errors
+    ///
+    /// If signing fails.
+    pub fn sign_commit(&self, body: &[u8]) -> Result<Vec<u8>> {
+        let signature = self.sign(body)?;
+
+        let header_end = body
+            .windows(2)
+            .position(|pair| pair == b"\n\n")
+            .map(|i| i + 1)
+            .unwrap_or(body.len());
+
+        let mut signed = Vec::with_capacity(body.len() + signature.len() + 16);
+        signed.extend(&body[..header_end]);
+        signed.extend(b"gpgsig ");
+        signed.extend(signature.trim_end().replace('\n', "\n ").as_bytes());
+        signed.push(b'\n');
+        signed.extend(&body[header_end..]);
+        Ok(signed)
+    }
+}
+
+/// Runs a signing `command` with `payload` on stdin, returning its stdout and
+/// stderr.
+fn run_signer(mut command: Command, payload: &[u8]) -> Result<(String, String)> {
+    trace!("Running signer: {:?}", command);
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .wrap_err_with(|| format!("Failed to run signing program: {:?}", command))?;
+
+    child
+        .stdin
+        .take()
+        .expect("stdin was piped")
+        .write_all(payload)?;
+
+    let output = child.wait_with_output()?;
+    let stdout = String::from_utf8(output.stdout).wrap_err("Signature wasn't UTF-8")?;
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+
+    if !output.status.success() || stdout.is_empty() {
+        bail!(
+            "Signing program {:?} failed ({}):\n{}",
+            command.get_program(),
+            output.status,
+            stderr
+        );
+    }
+
+    Ok((stdout, stderr))
+}
+
+/// Expands a leading `~/` in a configured path, like Git does.
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), home::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
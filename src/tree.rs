@@ -0,0 +1,572 @@
+//! Building Git blob and tree objects natively, without going through a
+//! libgit2 [`Index`][git2::Index].
+
+use {
+    crate::{
+        git2::{OidExt, RepositoryExt},
+        guardrails::Guardrails,
+        lfs,
+    },
+    eyre::{bail, eyre, Result},
+    git2::{AttrCheckFlags, AttrValue, ObjectType, Oid, Repository},
+    rayon::iter::{IntoParallelRefIterator, ParallelIterator},
+    std::{
+        collections::{BTreeMap, HashMap},
+        fs,
+        path::Path,
+    },
+    tracing::{debug, instrument, trace, warn},
+};
+
+/// The mode of a regular file in a tree.
+pub const MODE_FILE: i32 = 0o100_644;
+/// The mode of an executable file in a tree.
+pub const MODE_EXECUTABLE: i32 = 0o100_755;
+/// The mode of a symbolic link in a tree.
+pub const MODE_SYMLINK: i32 = 0o120_000;
+/// The mode of a subtree in a tree.
+pub const MODE_TREE: i32 = 0o040_000;
+/// The mode of a submodule's commit (a gitlink) in a tree.
+pub const MODE_GITLINK: i32 = 0o160_000;
+
+/// An entry in a Git tree object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The file mode, such as [`MODE_FILE`] or [`MODE_TREE`].
+    pub mode: i32,
+    /// The file name, which can't contain `/` or NUL.
+    pub name: Vec<u8>,
+    /// The ID of the blob, tree, or commit this entry points to.
+    pub id: Oid,
+}
+
+impl Entry {
+    /// The key Git sorts tree entries by: the name, with a `/` appended for
+    /// subtrees so that `foo/` sorts after `foo.txt`.
+    fn sort_key(&self) -> Vec<u8> {
+        let mut key = self.name.clone();
+        if self.mode == MODE_TREE {
+            key.push(b'/');
+        }
+        key
+    }
+}
+
+/// Sorts tree entries into Git's canonical order and serializes them into the
+/// body of a tree object.
+#[must_use]
+pub fn serialize(entries: &mut [Entry]) -> Vec<u8> {
+    entries.sort_by_cached_key(Entry::sort_key);
+
+    let mut body = Vec::with_capacity(entries.len() * 48);
+    for entry in entries.iter() {
+        body.extend(format!("{:o} ", entry.mode).as_bytes());
+        body.extend(&entry.name);
+        body.push(0);
+        body.extend(entry.id.as_bytes());
+    }
+    body
+}
+
+/// A file in the working tree, found while walking it.
+#[derive(Debug)]
+struct WorkingFile {
+    /// The path relative to the root of the working tree, with `/`
+    /// separators.
+    path: String,
+    /// The mode it will have in the tree.
+    mode: i32,
+    /// Whether it's tracked by Git LFS, so that we store a pointer instead.
+    lfs: bool,
+}
+
+/// A directory in the tree we're building.
+#[derive(Debug, Default)]
+struct Directory {
+    /// Files directly inside of this directory, by name.
+    files: BTreeMap<String, (i32, Oid)>,
+    /// Subdirectories, by name.
+    directories: BTreeMap<String, Self>,
+}
+
+/// The files and submodules found while walking the working tree.
+#[derive(Debug, Default)]
+struct Walk {
+    /// The files to hash.
+    files: Vec<WorkingFile>,
+    /// Submodules, by path, and the commits the index records for them.
+    gitlinks: Vec<(String, Oid)>,
+}
+
+/// Hashes the current contents of the working tree into a tree ID, as though
+/// everything inside of it had been added to the index, but without using the
+/// index. Blobs are read and hashed in parallel.
+///
+/// Ignored files are skipped unless they're already tracked. Submodules keep
+/// the commit already recorded for them in the index, even if they haven't
+/// been initialized, or are skipped with a warning logged if there isn't one. Files tracked by Git LFS are replaced by
+/// LFS pointers, but their content isn't stored. Nothing is checked against
+/// [`Guardrails`].
+///
+/// This is only provided for library users: `save` still builds its trees
+/// through an index, since it also needs pathspecs and guardrails, and this
+/// hasn't been measured to be faster for it.
+///
+/// In debug builds, the result is cross-checked against the tree built by
+/// adding the working tree to a copy of the index, without writing anything.
+///
+/// If `write` is false nothing is written to the object database, so the
+/// resulting tree may not exist. If it's true, any missing blobs and trees are
+/// written.
+///
+/// # Errors
+///
+/// If the repository is bare, if the working tree can't be read, or if any
+/// file would be changed by Git's line ending or `ident` filters, which this
+/// doesn't support.
+///
+/// # Panics
+///
+/// In debug builds, if the tree doesn't match the one built with an index.
+#[instrument(level = "debug", skip(repo))]
+pub fn working_tree_id(repo: &Repository, write: bool) -> Result<Oid> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| eyre!("Repository is bare!"))?
+        .to_path_buf();
+
+    let tracked = repo
+        .index()?
+        .iter()
+        .map(|entry| {
+            (
+                String::from_utf8_lossy(&entry.path).into_owned(),
+                (entry.mode, entry.id),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    let autocrlf = match repo.config()?.get_string("core.autocrlf") {
+        Ok(autocrlf) => matches!(autocrlf.to_ascii_lowercase().as_str(), "true" | "input"),
+        Err(_) => false,
+    };
+
+    let mut found = Walk::default();
+    walk(repo, &workdir, "", &tracked, autocrlf, &mut found)?;
+    let Walk { files, gitlinks } = found;
+    debug!(
+        "Found {} files and {} submodules in the working tree.",
+        files.len(),
+        gitlinks.len()
+    );
+
+    let hashed = files
+        .par_iter()
+        .map(|file| -> Result<Oid> {
+            let content = read_blob(&workdir, file)?;
+            Ok(Oid::for_object("blob", &content))
+        })
+        .collect::<Result<Vec<Oid>>>()?;
+
+    let odb = repo.odb()?;
+    let mut root = Directory::default();
+    let entries = files
+        .iter()
+        .zip(hashed)
+        .map(|(file, id)| (&file.path, file.mode, id, Some(file)))
+        .chain(
+            gitlinks
+                .iter()
+                .map(|(path, id)| (path, MODE_GITLINK, *id, None)),
+        );
+    for (path, mode, id, file) in entries {
+        if let Some(file) = file {
+            if write && !odb.exists(id) {
+                let content = read_blob(&workdir, file)?;
+                if odb.write(ObjectType::Blob, &content)? != id {
+                    bail!("{} changed while it was being saved, aborting.", file.path);
+                }
+                trace!("Wrote blob {} for {}", id, file.path);
+            }
+        }
+
+        let (parents, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let mut directory = &mut root;
+        for component in parents.split('/').filter(|component| !component.is_empty()) {
+            directory = directory
+                .directories
+                .entry(component.to_string())
+                .or_default();
+        }
+        directory.files.insert(name.to_string(), (mode, id));
+    }
+
+    let id = build_tree(repo, root, write)?.unwrap_or_else(|| Oid::for_object("tree", &[]));
+    if write && !odb.exists(id) {
+        odb.write(ObjectType::Tree, &[])?;
+    }
+
+    if cfg!(debug_assertions) {
+        // cross-check with git2, through a handle that only writes to memory
+        let snapshot = Repository::open(repo.path())?;
+        snapshot.write_objects_in_memory()?;
+        let expected = snapshot
+            .working_index_snapshot(&[], &Guardrails::disabled())?
+            .write_tree_to(&snapshot)?;
+        assert_eq!(
+            id, expected,
+            "working_tree_id produced a different tree than the index"
+        );
+    }
+
+    Ok(id)
+}
+
+/// Recursively collects the files and submodules in `dir` (at `prefix`
+/// relative to the root of the working tree) that should be included in a
+/// snapshot.
+fn walk(
+    repo: &Repository,
+    dir: &Path,
+    prefix: &str,
+    tracked: &HashMap<String, (u32, Oid)>,
+    autocrlf: bool,
+    found: &mut Walk,
+) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(fs::DirEntry::file_name);
+
+    for entry in entries {
+        let name = entry.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| eyre!("Path isn't UTF-8: {:?}", entry.path()))?;
+        if name == ".git" {
+            continue;
+        }
+        let path = format!("{}{}", prefix, name);
+        let metadata = entry.path().symlink_metadata()?;
+
+        if metadata.is_dir() {
+            // checked first, since an uninitialized submodule is just an empty
+            // directory
+            if let Some(&(mode, id)) = tracked.get(&path) {
+                if i32::try_from(mode)? == MODE_GITLINK {
+                    trace!("Keeping submodule {} at {}", path, id);
+                    found.gitlinks.push((path, id));
+                    continue;
+                }
+            }
+            if entry.path().join(".git").exists() {
+                warn!("Encountered a Git submodule; skipping it: {}", path);
+                continue;
+            }
+            let dir_prefix = format!("{}/", path);
+            if repo.is_path_ignored(&dir_prefix)?
+                && !tracked
+                    .keys()
+                    .any(|tracked| tracked.starts_with(&dir_prefix))
+            {
+                trace!("Skipping ignored directory: {}", path);
+                continue;
+            }
+            walk(repo, &entry.path(), &dir_prefix, tracked, autocrlf, found)?;
+            continue;
+        }
+
+        if repo.is_path_ignored(&path)? && !tracked.contains_key(&path) {
+            trace!("Skipping ignored file: {}", path);
+            continue;
+        }
+
+        let mode = if metadata.file_type().is_symlink() {
+            MODE_SYMLINK
+        } else if metadata.is_file() {
+            if is_executable(&metadata) {
+                MODE_EXECUTABLE
+            } else {
+                MODE_FILE
+            }
+        } else {
+            trace!("Skipping special file: {}", path);
+            continue;
+        };
+        let lfs = mode != MODE_SYMLINK && lfs::is_tracked(repo, Path::new(&path));
+        if mode != MODE_SYMLINK && !lfs {
+            if let Some(filter) = filter(repo, Path::new(&path), autocrlf)? {
+                bail!(
+                    "{} would be changed by Git's {} filter, which isn't supported without an \
+                     index.",
+                    path,
+                    filter
+                );
+            }
+        }
+
+        found.files.push(WorkingFile { path, mode, lfs });
+    }
+
+    Ok(())
+}
+
+/// Returns the name of the content filter that Git would apply to `path` when
+/// adding it to the index, if any: line ending conversion (from the `text` or
+/// `eol` attributes, or `core.autocrlf`), or `ident`. Git LFS files are
+/// handled separately, and libgit2 doesn't run any other filter drivers.
+fn filter(repo: &Repository, path: &Path, autocrlf: bool) -> Result<Option<&'static str>> {
+    let attribute = |name| -> Result<AttrValue<'_>> {
+        Ok(AttrValue::from_string(repo.get_attr(
+            path,
+            name,
+            AttrCheckFlags::FILE_THEN_INDEX,
+        )?))
+    };
+
+    let text = match attribute("text")? {
+        AttrValue::False => false,
+        AttrValue::Unspecified => autocrlf || !matches!(attribute("eol")?, AttrValue::Unspecified),
+        _ => true,
+    };
+    Ok(if text {
+        Some("line ending")
+    } else if matches!(attribute("ident")?, AttrValue::True) {
+        Some("ident")
+    } else {
+        None
+    })
+}
+
+/// Reads the content of the blob that represents a file in the working tree.
+fn read_blob(workdir: &Path, file: &WorkingFile) -> Result<Vec<u8>> {
+    let path = workdir.join(&file.path);
+    if file.mode == MODE_SYMLINK {
+        let target = fs::read_link(&path)?;
+        let target = target
+            .to_str()
+            .ok_or_else(|| eyre!("Symlink target isn't UTF-8: {:?}", target))?;
+        Ok(target.as_bytes().to_vec())
+    } else if file.lfs {
+        Ok(lfs::clean(&path)?.1)
+    } else {
+        Ok(fs::read(&path)?)
+    }
+}
+
+/// Hashes (and optionally writes) the tree for a [`Directory`], returning
+/// `None` if it's empty, since Git doesn't track empty directories.
+fn build_tree(repo: &Repository, directory: Directory, write: bool) -> Result<Option<Oid>> {
+    let mut entries = vec![];
+    for (name, subdirectory) in directory.directories {
+        if let Some(id) = build_tree(repo, subdirectory, write)? {
+            entries.push(Entry {
+                mode: MODE_TREE,
+                name: name.into_bytes(),
+                id,
+            });
+        }
+    }
+    for (name, (mode, id)) in directory.files {
+        entries.push(Entry {
+            mode,
+            name: name.into_bytes(),
+            id,
+        });
+    }
+    if entries.is_empty() {
+        return Ok(None);
+    }
+
+    let body = serialize(&mut entries);
+    let id = Oid::for_object("tree", &body);
+
+    if write {
+        let odb = repo.odb()?;
+        if !odb.exists(id) {
+            odb.write(ObjectType::Tree, &body)?;
+        }
+    }
+
+    Ok(Some(id))
+}
+
+/// Whether a file should be committed as executable.
+#[cfg(unix)]
+pub(crate) fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+/// Whether a file should be committed as executable.
+#[cfg(not(unix))]
+pub(crate) fn is_executable(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::git2::RepositoryExt, git2::Signature};
+
+    /// Commits everything in the working tree of `repo`, like `git add -A`.
+    fn commit_all(repo: &Repository) -> Result<Oid> {
+        let mut index = repo.index()?;
+        index.add_all(["*"], git2::IndexAddOption::DEFAULT, None)?;
+        index.write()?;
+        let tree = repo.find_tree(index.write_tree()?)?;
+        let signature = Signature::now("Tester", "tester@example.com")?;
+        let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        Ok(repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "Test",
+            &tree,
+            &parent.iter().collect::<Vec<_>>(),
+        )?)
+    }
+
+    #[test]
+    fn matches_the_index() -> Result<()> {
+        let repo = Repository::temporary()?;
+        let workdir = repo.workdir().unwrap().to_path_buf();
+        fs::create_dir_all(workdir.join("src/nested"))?;
+        fs::create_dir_all(workdir.join("target"))?;
+        fs::write(workdir.join(".gitignore"), "target/\n*.log\n")?;
+        fs::write(workdir.join(".gitattributes"), "*.bin filter=lfs -text\n")?;
+        fs::write(workdir.join("README.md"), "# Test\n")?;
+        fs::write(workdir.join("src/lib.rs"), "")?;
+        fs::write(workdir.join("src/nested/mod.rs"), "// nested\n")?;
+        fs::write(workdir.join("src.txt"), "sorts before src/\n")?;
+        fs::write(workdir.join("deleted.txt"), "deleted\n")?;
+        fs::write(workdir.join("target/output"), "ignored")?;
+        fs::write(workdir.join("tracked.log"), "tracked")?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            fs::write(workdir.join("run.sh"), "#!/bin/sh\n")?;
+            fs::set_permissions(workdir.join("run.sh"), fs::Permissions::from_mode(0o755))?;
+            std::os::unix::fs::symlink("README.md", workdir.join("link"))?;
+        }
+        let mut index = repo.index()?;
+        index.add_path(Path::new("tracked.log"))?;
+        index.write()?;
+        commit_all(&repo)?;
+
+        // a submodule recorded in the index, and one that isn't
+        let submodule = Repository::init(workdir.join("lib"))?;
+        fs::write(workdir.join("lib/lib.rs"), "")?;
+        let gitlink = commit_all(&submodule)?;
+        Repository::init(workdir.join("vendor"))?;
+        let mut index = repo.index()?;
+        index.add(&git2::IndexEntry {
+            ctime: git2::IndexTime::new(0, 0),
+            mtime: git2::IndexTime::new(0, 0),
+            dev: 0,
+            ino: 0,
+            mode: 0o160_000,
+            uid: 0,
+            gid: 0,
+            file_size: 0,
+            id: gitlink,
+            flags: 0,
+            flags_extended: 0,
+            path: b"lib".to_vec(),
+        })?;
+        index.write()?;
+
+        fs::remove_file(workdir.join("deleted.txt"))?;
+        fs::write(workdir.join("README.md"), "# Changed\n")?;
+        fs::write(workdir.join("src/new.rs"), "// new\n")?;
+        fs::write(workdir.join("debug.log"), "ignored")?;
+        fs::write(workdir.join("data.bin"), "hello")?;
+
+        let expected = repo
+            .working_index(&[], &Guardrails::disabled())?
+            .write_tree()?;
+        assert_eq!(repo.write_working_tree()?, expected);
+        assert_eq!(repo.working_tree_id()?, expected);
+
+        let tree = repo.find_tree(expected)?;
+        assert_eq!(tree.get_name("lib").unwrap().id(), gitlink);
+        assert_eq!(tree.get_name("lib").unwrap().filemode(), MODE_GITLINK);
+        assert!(tree.get_name("vendor").is_none());
+        assert!(tree.get_name("deleted.txt").is_none());
+        assert!(tree.get_name("tracked.log").is_some());
+        assert!(tree.get_name("debug.log").is_none());
+        let pointer = repo.find_blob(tree.get_name("data.bin").unwrap().id())?;
+        assert_eq!(
+            pointer.content(),
+            lfs::Pointer::for_content(b"hello").to_bytes()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_content_filters() -> Result<()> {
+        let repo = Repository::temporary()?;
+        let workdir = repo.workdir().unwrap().to_path_buf();
+        fs::write(workdir.join("file.txt"), "line\r\n")?;
+        fs::write(workdir.join("file.bin"), "binary\r\n")?;
+        fs::write(workdir.join(".gitattributes"), "*.bin -text\n")?;
+        repo.working_tree_id()?;
+
+        fs::write(workdir.join(".gitattributes"), "*.bin -text\n*.txt ident\n")?;
+        assert!(repo.working_tree_id().is_err());
+
+        fs::write(
+            workdir.join(".gitattributes"),
+            "*.bin -text\n*.txt eol=lf\n",
+        )?;
+        assert!(repo.working_tree_id().is_err());
+
+        fs::write(workdir.join(".gitattributes"), "*.bin -text\n")?;
+        repo.config()?.set_str("core.autocrlf", "input")?;
+        assert!(repo.working_tree_id().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_uninitialized_submodules() -> Result<()> {
+        let repo = Repository::temporary()?;
+        let workdir = repo.workdir().unwrap().to_path_buf();
+        fs::write(workdir.join("README.md"), "# Test\n")?;
+        fs::write(
+            workdir.join(".gitmodules"),
+            "[submodule \"lib\"]\n\tpath = lib\n\turl = https://example.com/lib.git\n",
+        )?;
+        commit_all(&repo)?;
+
+        // as cloned without --recurse-submodules: an empty directory
+        fs::create_dir(workdir.join("lib"))?;
+        let gitlink = Oid::from_str("0123456789abcdef0123456789abcdef01234567")?;
+        let mut index = repo.index()?;
+        index.add(&git2::IndexEntry {
+            ctime: git2::IndexTime::new(0, 0),
+            mtime: git2::IndexTime::new(0, 0),
+            dev: 0,
+            ino: 0,
+            mode: 0o160_000,
+            uid: 0,
+            gid: 0,
+            file_size: 0,
+            id: gitlink,
+            flags: 0,
+            flags_extended: 0,
+            path: b"lib".to_vec(),
+        })?;
+        index.write()?;
+
+        let expected = repo
+            .working_index(&[], &Guardrails::disabled())?
+            .write_tree()?;
+        assert_eq!(repo.working_tree_id()?, expected);
+
+        let tree = repo.find_tree(expected)?;
+        assert_eq!(tree.get_name("lib").unwrap().id(), gitlink);
+        assert_eq!(tree.get_name("lib").unwrap().filemode(), MODE_GITLINK);
+
+        Ok(())
+    }
+}